@@ -0,0 +1,56 @@
+use chrono::Local;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use uuid::Uuid;
+
+/// ## Summary
+/// CRAM-MD5(RFC 2195)のチャレンジを作成する
+///
+/// ## Returns
+/// `<ランダム値.タイムスタンプ@ホスト名>` 形式の文字列
+pub fn challenge() -> String {
+    format!(
+        "<{}.{}@rust-mail-server>",
+        Uuid::new_v4().simple(),
+        Local::now().timestamp()
+    )
+}
+
+/// ## Summary
+/// チャレンジに対する応答のダイジェストを計算する
+///
+/// ## Returns
+/// HMAC-MD5(パスワード, チャレンジ)の16進文字列(小文字)
+pub fn digest(password: &str, challenge: &str) -> String {
+    let mut mac =
+        Hmac::<Md5>::new_from_slice(password.as_bytes()).expect("HMACは任意の長さの鍵を受け付ける");
+    mac.update(challenge.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_matches_rfc2195_example() {
+        assert_eq!(
+            digest(
+                "tanstaaftanstaaf",
+                "<1896.697170952@postoffice.reston.mci.net>"
+            ),
+            "b913a602c7eda7a495b4e6e7334d3890"
+        );
+    }
+
+    #[test]
+    fn challenge_is_unique() {
+        let challenge = challenge();
+        assert!(challenge.starts_with('<') && challenge.ends_with("@rust-mail-server>"));
+        assert_ne!(challenge, super::challenge());
+    }
+}
@@ -0,0 +1,212 @@
+use log::{info, warn};
+use rumbok::Data;
+
+pub mod cram_md5;
+pub mod oauth;
+pub mod scram;
+pub mod user_db;
+
+use user_db::UserDatabase;
+
+/// 認証を必須にするかどうかのポリシー
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AuthPolicy {
+    /// 認証するまでMAIL FROMを受け付けない(530)
+    pub require_auth: bool,
+    /// STARTTLS(またはSMTPS)でTLSになるまでAUTH・MAIL FROMを受け付けない(538)
+    pub require_tls: bool,
+    /// 検証済みのクライアント証明書で接続したセッションを認証済みとして扱う
+    pub client_cert_auth: bool,
+}
+
+#[derive(Data)]
+pub struct Auth {
+    authenticated: bool,
+    username: String,
+}
+
+const SUCCESS_MESSAGE_BYTES: &[u8] = b"235 Authentication successful\r\n";
+pub const FAILED_MESSAGE_BYTES: &[u8] = b"535 Authentication failed\r\n";
+const SEPERATOR_LENGTH: usize = 3;
+
+impl Auth {
+    /// ## Summary
+    /// PLAIN(RFC 4616)の資格情報で認証する
+    ///
+    /// ## Parameters
+    /// - `credentials`: base64デコード済みの `authzid\0authcid\0passwd`
+    pub fn plain(&mut self, credentials: &[u8], users: Option<&UserDatabase>) -> &'static [u8] {
+        let split: Vec<&[u8]> = credentials.split(|&b| b == 0).collect();
+        if split.len() < SEPERATOR_LENGTH {
+            return FAILED_MESSAGE_BYTES;
+        }
+
+        let username = String::from_utf8_lossy(split[1]).to_string();
+        let password = String::from_utf8_lossy(split[2]).to_string();
+
+        if username.is_empty() {
+            return b"501 Missing credentials\r\n";
+        }
+
+        self.login(username, &password, users)
+    }
+
+    /// ## Summary
+    /// ユーザー名とパスワードで認証する
+    ///
+    /// ## Note
+    /// ユーザーデータベースが設定されていない場合はどの資格情報でも認証成功とする
+    /// パスワードはログに出力しない
+    ///
+    /// ## Returns
+    /// クライアントへの応答(235 または 535)
+    pub fn login(
+        &mut self,
+        username: String,
+        password: &str,
+        users: Option<&UserDatabase>,
+    ) -> &'static [u8] {
+        if let Some(users) = users {
+            if !users.verify(&username, password) {
+                warn!("認証失敗 username:{}", &username);
+                return FAILED_MESSAGE_BYTES;
+            }
+        }
+
+        self.complete(username)
+    }
+
+    /// ## Summary
+    /// CRAM-MD5の応答を検証して認証する
+    ///
+    /// ## Parameters
+    /// - `challenge`: サーバーが送ったチャレンジ
+    /// - `response`: クライアントの応答(デコード済みの `ユーザー名 ダイジェスト`)
+    ///
+    /// ## Returns
+    /// クライアントへの応答(235 / 501 / 535)
+    pub fn cram_md5(
+        &mut self,
+        challenge: &str,
+        response: &str,
+        users: Option<&UserDatabase>,
+    ) -> &'static [u8] {
+        let Some((username, digest)) = response.rsplit_once(' ') else {
+            return b"501 Syntax: <username> <digest>\r\n";
+        };
+
+        if let Some(users) = users {
+            if !users.verify_cram_md5(username, challenge, digest) {
+                warn!("認証失敗(CRAM-MD5) username:{}", username);
+                return FAILED_MESSAGE_BYTES;
+            }
+        }
+
+        self.complete(username.to_string())
+    }
+
+    /// ## Summary
+    /// 検証済みのユーザーとして認証済みにする
+    ///
+    /// ## Returns
+    /// クライアントへの応答(235)
+    pub fn complete(&mut self, username: String) -> &'static [u8] {
+        info!("認証成功 username:{}", &username);
+
+        self.username = username;
+        self.authenticated = true;
+
+        SUCCESS_MESSAGE_BYTES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users() -> UserDatabase {
+        UserDatabase::parse("alice:{PLAIN}secret\n").unwrap()
+    }
+
+    fn plain(users: Option<&UserDatabase>, credentials: &[u8]) -> (Auth, &'static [u8]) {
+        let mut auth = Auth::default();
+        let response = auth.plain(credentials, users);
+        (auth, response)
+    }
+
+    #[test]
+    fn plain_authenticates_valid_credentials() {
+        let users = users();
+        let (auth, response) = plain(Some(&users), b"\0alice\0secret");
+        assert_eq!(response, SUCCESS_MESSAGE_BYTES);
+        assert!(*auth.get_authenticated());
+        assert_eq!(auth.get_username(), "alice");
+
+        // 認可ID付きでも認証IDで認証する
+        let (_, response) = plain(Some(&users), b"admin\0alice\0secret");
+        assert_eq!(response, SUCCESS_MESSAGE_BYTES);
+    }
+
+    #[test]
+    fn plain_rejects_invalid_credentials() {
+        let users = users();
+        let (auth, response) = plain(Some(&users), b"\0alice\0wrong");
+        assert_eq!(response, FAILED_MESSAGE_BYTES);
+        assert!(!*auth.get_authenticated());
+        assert_eq!(
+            plain(Some(&users), b"\0bob\0secret").1,
+            FAILED_MESSAGE_BYTES
+        );
+        assert_eq!(plain(Some(&users), b"alice secret").1, FAILED_MESSAGE_BYTES);
+        assert!(plain(Some(&users), b"\0\0secret").1.starts_with(b"501 "));
+    }
+
+    #[test]
+    fn login_accepts_anyone_without_user_database() {
+        let mut auth = Auth::default();
+        assert_eq!(
+            auth.login("anyone".to_string(), "anything", None),
+            SUCCESS_MESSAGE_BYTES
+        );
+        assert!(*auth.get_authenticated());
+    }
+
+    #[test]
+    fn login_verifies_password() {
+        let users = users();
+        let mut auth = Auth::default();
+        assert_eq!(
+            auth.login("alice".to_string(), "wrong", Some(&users)),
+            FAILED_MESSAGE_BYTES
+        );
+        assert!(!*auth.get_authenticated());
+        assert_eq!(
+            auth.login("alice".to_string(), "secret", Some(&users)),
+            SUCCESS_MESSAGE_BYTES
+        );
+        assert!(*auth.get_authenticated());
+    }
+
+    #[test]
+    fn cram_md5_verifies_digest() {
+        let users = users();
+        let challenge = cram_md5::challenge();
+        let digest = cram_md5::digest("secret", &challenge);
+
+        let mut auth = Auth::default();
+        let wrong = format!("alice {}", cram_md5::digest("wrong", &challenge));
+        assert_eq!(
+            auth.cram_md5(&challenge, &wrong, Some(&users)),
+            FAILED_MESSAGE_BYTES
+        );
+        assert!(auth
+            .cram_md5(&challenge, "alice", Some(&users))
+            .starts_with(b"501 "));
+        let response = format!("alice {}", digest);
+        assert_eq!(
+            auth.cram_md5(&challenge, &response, Some(&users)),
+            SUCCESS_MESSAGE_BYTES
+        );
+        assert_eq!(auth.get_username(), "alice");
+    }
+}
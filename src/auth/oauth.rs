@@ -0,0 +1,344 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine};
+use chrono::Local;
+use hmac::{Hmac, Mac};
+use log::info;
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use super::user_db::constant_time_eq;
+
+/// SASLの値の区切り文字(RFC 7628)
+const SEPARATOR: char = '\x01';
+const BEARER_PREFIX: &str = "auth=Bearer ";
+
+/// OAuthを使うSASLメカニズム
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OAuthMechanism {
+    /// Google / Microsoftの独自形式
+    XOAuth2,
+    /// RFC 7628
+    OAuthBearer,
+}
+
+/// クライアントから受け取ったOAuthの資格情報
+pub struct OAuthCredentials {
+    /// 認証するユーザー(OAUTHBEARERでは省略できる)
+    pub username: Option<String>,
+    /// Bearerトークン
+    pub token: String,
+}
+
+/// ## Summary
+/// OAuthのアクセストークンを検証する
+///
+/// ## Note
+/// 外部のIDプロバイダーで検証したい場合はこのtraitを実装して `SessionContext` に渡す
+pub trait TokenValidator: Send + Sync {
+    /// ## Summary
+    /// トークンを検証する
+    ///
+    /// ## Parameters
+    /// - `username`: クライアントが指定したユーザー(省略された場合はNone)
+    /// - `token`: Bearerトークン
+    ///
+    /// ## Returns
+    /// 認証されたユーザー名
+    fn validate(&self, username: Option<&str>, token: &str) -> Result<String>;
+}
+
+/// ## Summary
+/// ローカルの設定だけでトークンを検証するバリデーター
+///
+/// ## Note
+/// 静的なトークンの一覧と、HS256で署名されたJWTに対応する
+/// JWTは署名と `exp` / `nbf` を検証し、`sub`(なければ `email`)をユーザー名とする
+///
+/// トークンの一覧は1行に1トークン、`ユーザー名:トークン` の形式で記述する
+/// (ユーザー名を省略したトークンはどのユーザーとしても認証できる)
+pub struct LocalTokenValidator {
+    /// トークン → 対応するユーザー(Noneならどのユーザーでも可)
+    tokens: HashMap<String, Option<String>>,
+    /// JWTの署名鍵
+    jwt_secret: Option<Vec<u8>>,
+}
+
+impl LocalTokenValidator {
+    pub fn new(tokens: HashMap<String, Option<String>>, jwt_secret: Option<Vec<u8>>) -> Self {
+        Self { tokens, jwt_secret }
+    }
+
+    /// ## Summary
+    /// トークンの一覧ファイルとJWTの署名鍵からバリデーターを作成する
+    ///
+    /// ## Parameters
+    /// - `tokens_file`: トークンの一覧ファイル
+    /// - `jwt_secret`: JWT(HS256)の署名鍵
+    pub fn load(tokens_file: Option<&Path>, jwt_secret: Option<String>) -> Result<Self> {
+        let mut tokens = HashMap::new();
+        if let Some(path) = tokens_file {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("トークンの一覧 {:?} を読み込めません", path))?;
+            for line in content.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match line.split_once(':') {
+                    Some((username, token)) => {
+                        tokens.insert(token.to_string(), Some(username.to_string()))
+                    }
+                    None => tokens.insert(line.to_string(), None),
+                };
+            }
+            info!(
+                "トークンの一覧 {:?} から {} 件のトークンを読み込みました",
+                path,
+                tokens.len()
+            );
+        }
+
+        Ok(Self::new(tokens, jwt_secret.map(String::into_bytes)))
+    }
+
+    /// HS256で署名されたJWTを検証して、トークンの持ち主を返す
+    fn validate_jwt(&self, secret: &[u8], token: &str) -> Result<String> {
+        let parts: Vec<&str> = token.split('.').collect();
+        let [header, payload, signature] = parts[..] else {
+            bail!("JWTの形式ではありません");
+        };
+        let decode = |value: &str| general_purpose::URL_SAFE_NO_PAD.decode(value);
+
+        let header: Value = serde_json::from_slice(&decode(header)?)?;
+        if header["alg"] != "HS256" {
+            bail!("未対応のアルゴリズムです: {}", header["alg"]);
+        }
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret)?;
+        mac.update(format!("{}.{}", parts[0], parts[1]).as_bytes());
+        let expected = mac.finalize().into_bytes();
+        if !constant_time_eq(&expected, &decode(signature)?) {
+            bail!("JWTの署名が一致しません");
+        }
+
+        let claims: Value = serde_json::from_slice(&decode(payload)?)?;
+        let now = Local::now().timestamp();
+        if claims["exp"].as_i64().is_some_and(|exp| exp <= now) {
+            bail!("JWTの有効期限が切れています");
+        }
+        if claims["nbf"].as_i64().is_some_and(|nbf| nbf > now) {
+            bail!("JWTはまだ有効ではありません");
+        }
+        match claims["sub"].as_str().or(claims["email"].as_str()) {
+            Some(subject) => Ok(subject.to_string()),
+            None => bail!("JWTに sub / email がありません"),
+        }
+    }
+}
+
+impl TokenValidator for LocalTokenValidator {
+    fn validate(&self, username: Option<&str>, token: &str) -> Result<String> {
+        let owner = if let Some(owner) = self.tokens.get(token) {
+            owner.clone()
+        } else if let Some(secret) = &self.jwt_secret {
+            Some(self.validate_jwt(secret, token)?)
+        } else {
+            bail!("登録されていないトークンです");
+        };
+
+        match (username, owner) {
+            (Some(username), Some(owner)) if username != owner => {
+                bail!("トークンの持ち主({})とユーザーが一致しません", owner)
+            }
+            (Some(username), _) => Ok(username.to_string()),
+            (None, Some(owner)) => Ok(owner),
+            (None, None) => bail!("ユーザーを特定できません"),
+        }
+    }
+}
+
+impl OAuthMechanism {
+    /// ## Summary
+    /// クライアントの応答(デコード済み)から資格情報を取り出す
+    ///
+    /// ```text
+    /// XOAUTH2:     user=<ユーザー>^Aauth=Bearer <トークン>^A^A
+    /// OAUTHBEARER: n,a=<ユーザー>,^Ahost=...^Aport=...^Aauth=Bearer <トークン>^A^A
+    /// ```
+    pub fn parse(&self, response: &str) -> Result<OAuthCredentials> {
+        let (mut username, fields) = match self {
+            OAuthMechanism::XOAuth2 => (None, response),
+            OAuthMechanism::OAuthBearer => {
+                // gs2-header: チャネルバインディングフラグ,a=認可ID,
+                let mut parts = response.splitn(3, ',');
+                let (Some(binding), Some(authzid), Some(fields)) =
+                    (parts.next(), parts.next(), parts.next())
+                else {
+                    bail!("GS2ヘッダーの形式が不正です");
+                };
+                if binding != "n" && binding != "y" {
+                    bail!("チャネルバインディングには対応していません");
+                }
+                let username = authzid
+                    .strip_prefix("a=")
+                    .map(|value| value.replace("=2C", ",").replace("=3D", "="));
+                (username, fields)
+            }
+        };
+
+        let mut token = None;
+        for field in fields.split(SEPARATOR) {
+            if let Some(value) = field.strip_prefix(BEARER_PREFIX) {
+                token = Some(value.trim().to_string());
+            } else if let Some(value) = field.strip_prefix("user=") {
+                if *self == OAuthMechanism::XOAuth2 {
+                    username = Some(value.to_string());
+                }
+            }
+        }
+
+        match token {
+            Some(token) if !token.is_empty() => Ok(OAuthCredentials {
+                username: username.filter(|username| !username.is_empty()),
+                token,
+            }),
+            _ => bail!("Bearerトークンがありません"),
+        }
+    }
+
+    /// ## Summary
+    /// 認証失敗時にクライアントへ送るエラー(JSON)
+    ///
+    /// ## Note
+    /// クライアントはこれに空(OAUTHBEARERは ^A)の応答を返し、その後に535を受け取る
+    pub fn error_challenge(&self) -> String {
+        let status = match self {
+            OAuthMechanism::XOAuth2 => "401",
+            OAuthMechanism::OAuthBearer => "invalid_token",
+        };
+        json!({ "status": status, "schemes": "bearer" }).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"jwt-secret";
+
+    /// HS256で署名したJWTを作る
+    fn jwt(secret: &[u8], claims: Value) -> String {
+        let encode = |value: &[u8]| general_purpose::URL_SAFE_NO_PAD.encode(value);
+        let header = encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+        let payload = encode(claims.to_string().as_bytes());
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(format!("{}.{}", header, payload).as_bytes());
+        let signature = encode(&mac.finalize().into_bytes());
+        format!("{}.{}.{}", header, payload, signature)
+    }
+
+    fn validator() -> LocalTokenValidator {
+        let tokens = HashMap::from([
+            ("alice-token".to_string(), Some("alice".to_string())),
+            ("shared-token".to_string(), None),
+        ]);
+        LocalTokenValidator::new(tokens, Some(SECRET.to_vec()))
+    }
+
+    #[test]
+    fn parses_xoauth2() {
+        let credentials = OAuthMechanism::XOAuth2
+            .parse("user=alice@example.com\x01auth=Bearer token\x01\x01")
+            .unwrap();
+        assert_eq!(credentials.username.as_deref(), Some("alice@example.com"));
+        assert_eq!(credentials.token, "token");
+    }
+
+    #[test]
+    fn parses_oauthbearer() {
+        let response =
+            "n,a=a=2Cb@example.com,\x01host=mail.example.com\x01port=587\x01auth=Bearer token\x01\x01";
+        let credentials = OAuthMechanism::OAuthBearer.parse(response).unwrap();
+        assert_eq!(credentials.username.as_deref(), Some("a,b@example.com"));
+        assert_eq!(credentials.token, "token");
+
+        // 認可IDは省略できる(user=はOAUTHBEARERでは使わない)
+        let credentials = OAuthMechanism::OAuthBearer
+            .parse("n,,\x01user=x\x01auth=Bearer token\x01\x01")
+            .unwrap();
+        assert_eq!(credentials.username, None);
+    }
+
+    #[test]
+    fn rejects_malformed_responses() {
+        assert!(OAuthMechanism::XOAuth2.parse("user=alice\x01\x01").is_err());
+        assert!(OAuthMechanism::XOAuth2
+            .parse("user=alice\x01auth=Bearer \x01\x01")
+            .is_err());
+        assert!(OAuthMechanism::OAuthBearer
+            .parse("p=tls-unique,,\x01auth=Bearer token\x01\x01")
+            .is_err());
+        assert!(OAuthMechanism::OAuthBearer
+            .parse("auth=Bearer token")
+            .is_err());
+    }
+
+    #[test]
+    fn validates_static_tokens() {
+        let validator = validator();
+        assert_eq!(validator.validate(None, "alice-token").unwrap(), "alice");
+        assert_eq!(
+            validator.validate(Some("alice"), "alice-token").unwrap(),
+            "alice"
+        );
+        assert!(validator.validate(Some("bob"), "alice-token").is_err());
+        // ユーザーを限定しないトークン
+        assert_eq!(
+            validator.validate(Some("bob"), "shared-token").unwrap(),
+            "bob"
+        );
+        assert!(validator.validate(None, "shared-token").is_err());
+
+        let without_jwt = LocalTokenValidator::new(HashMap::new(), None);
+        assert!(without_jwt.validate(Some("alice"), "unknown").is_err());
+    }
+
+    #[test]
+    fn validates_hs256_jwt() {
+        let validator = validator();
+        let now = Local::now().timestamp();
+        let token = jwt(SECRET, json!({ "sub": "alice", "exp": now + 60 }));
+        assert_eq!(validator.validate(None, &token).unwrap(), "alice");
+        assert!(validator.validate(Some("bob"), &token).is_err());
+
+        let token = jwt(SECRET, json!({ "email": "carol@example.com" }));
+        assert_eq!(
+            validator.validate(None, &token).unwrap(),
+            "carol@example.com"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_jwt() {
+        let validator = validator();
+        let now = Local::now().timestamp();
+        let invalid = [
+            jwt(b"other-secret", json!({ "sub": "alice" })),
+            jwt(SECRET, json!({ "sub": "alice", "exp": now - 1 })),
+            jwt(SECRET, json!({ "sub": "alice", "nbf": now + 60 })),
+            jwt(SECRET, json!({ "name": "alice" })),
+            "not-a-jwt".to_string(),
+        ];
+        for token in invalid {
+            assert!(validator.validate(None, &token).is_err(), "{}", token);
+        }
+
+        // alg: none 等のHS256以外は署名を検証せずに拒否する
+        let header = general_purpose::URL_SAFE_NO_PAD.encode(br#"{"alg":"none"}"#);
+        let payload = general_purpose::URL_SAFE_NO_PAD.encode(br#"{"sub":"alice"}"#);
+        let token = format!("{}.{}.", header, payload);
+        assert!(validator.validate(None, &token).is_err());
+    }
+}
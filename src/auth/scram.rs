@@ -0,0 +1,375 @@
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use super::user_db::{constant_time_eq, UserDatabase};
+
+/// 平文パスワードから導出する場合の反復回数(RFC 7677の推奨値)
+pub const DEFAULT_ITERATIONS: u32 = 4096;
+
+/// SCRAM-SHA-256の検証に使う値(RFC 5802)
+///
+/// ユーザーデータベースにはDovecotと同じ
+/// `{SCRAM-SHA-256}反復回数,salt,StoredKey,ServerKey`(各値はbase64)の形式で保存する
+#[derive(Clone)]
+pub struct ScramCredential {
+    iterations: u32,
+    salt: Vec<u8>,
+    stored_key: Vec<u8>,
+    server_key: Vec<u8>,
+}
+
+impl ScramCredential {
+    /// ## Summary
+    /// パスワードからSCRAMの検証値を導出する
+    ///
+    /// ## Parameters
+    /// - `password`: パスワード
+    /// - `salt`: ソルト
+    /// - `iterations`: PBKDF2の反復回数
+    pub fn derive(password: &str, salt: &[u8], iterations: u32) -> Self {
+        let mut salted_password = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut salted_password);
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        Self {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: Sha256::digest(&client_key).to_vec(),
+            server_key: hmac_sha256(&salted_password, b"Server Key"),
+        }
+    }
+
+    /// ランダムなソルトでパスワードから検証値を導出する
+    pub fn generate(password: &str) -> Self {
+        Self::derive(password, &random_bytes(), DEFAULT_ITERATIONS)
+    }
+
+    /// `反復回数,salt,StoredKey,ServerKey` の形式から読み込む
+    pub fn parse(input: &str) -> Result<Self> {
+        let fields: Vec<&str> = input.split(',').collect();
+        let [iterations, salt, stored_key, server_key] = fields[..] else {
+            bail!("SCRAM-SHA-256 は 反復回数,salt,StoredKey,ServerKey の形式で指定してください");
+        };
+        let decode = |value: &str| general_purpose::STANDARD.decode(value);
+        Ok(Self {
+            iterations: iterations.parse().context("反復回数が不正です")?,
+            salt: decode(salt).context("saltが不正です")?,
+            stored_key: decode(stored_key).context("StoredKeyが不正です")?,
+            server_key: decode(server_key).context("ServerKeyが不正です")?,
+        })
+    }
+
+    /// パスワードが一致するか検証する(PLAIN / LOGIN用)
+    pub fn verify_password(&self, password: &str) -> bool {
+        let derived = Self::derive(password, &self.salt, self.iterations);
+        constant_time_eq(&derived.stored_key, &self.stored_key)
+    }
+}
+
+impl std::fmt::Display for ScramCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let encode = |value: &[u8]| general_purpose::STANDARD.encode(value);
+        write!(
+            f,
+            "{},{},{},{}",
+            self.iterations,
+            encode(&self.salt),
+            encode(&self.stored_key),
+            encode(&self.server_key)
+        )
+    }
+}
+
+/// SCRAM-SHA-256のサーバー側のやり取り(RFC 5802 / RFC 7677)
+///
+/// ```text
+/// C: n,,n=user,r=<client nonce>
+/// S: r=<client nonce><server nonce>,s=<salt>,i=<反復回数>
+/// C: c=biws,r=<nonce>,p=<ClientProof>
+/// S: v=<ServerSignature>
+/// ```
+pub struct ScramSha256 {
+    username: String,
+    gs2_header: String,
+    nonce: String,
+    client_first_bare: String,
+    server_first: String,
+    /// ユーザーが存在しない場合はダミーの値で最後まで進めて失敗させる
+    credential: Option<ScramCredential>,
+}
+
+impl ScramSha256 {
+    /// ## Summary
+    /// クライアントの最初のメッセージを受け取る
+    ///
+    /// ## Returns
+    /// やり取りの状態とサーバーの最初のメッセージ
+    pub fn start(client_first: &str, users: &UserDatabase) -> Result<(Self, String)> {
+        // gs2-header: チャネルバインディングフラグ,認可ID,
+        let mut parts = client_first.splitn(3, ',');
+        let (Some(binding), Some(authzid), Some(client_first_bare)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            bail!("client-first-messageの形式が不正です");
+        };
+        if binding.starts_with("p=") {
+            bail!("チャネルバインディングには対応していません");
+        }
+        if binding != "n" && binding != "y" {
+            bail!("GS2ヘッダーが不正です: {}", binding);
+        }
+
+        let mut username = None;
+        let mut client_nonce = None;
+        for attribute in client_first_bare.split(',') {
+            if let Some(value) = attribute.strip_prefix("n=") {
+                username = Some(decode_saslname(value)?);
+            } else if let Some(value) = attribute.strip_prefix("r=") {
+                client_nonce = Some(value);
+            } else if attribute.starts_with("m=") {
+                bail!("未対応の拡張です: {}", attribute);
+            }
+        }
+        let (Some(username), Some(client_nonce)) = (username, client_nonce) else {
+            bail!("ユーザー名またはnonceがありません");
+        };
+        if let Some(authzid) = authzid.strip_prefix("a=") {
+            if decode_saslname(authzid)? != username {
+                bail!("別ユーザーとしての認可には対応していません");
+            }
+        }
+
+        let credential = users.scram_credential(&username);
+        let (salt, iterations) = match &credential {
+            Some(credential) => (credential.salt.clone(), credential.iterations),
+            None => (random_bytes(), DEFAULT_ITERATIONS),
+        };
+        let nonce = format!("{}{}", client_nonce, Uuid::new_v4().simple());
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            general_purpose::STANDARD.encode(&salt),
+            iterations
+        );
+
+        let scram = Self {
+            username,
+            gs2_header: format!("{},{},", binding, authzid),
+            nonce,
+            client_first_bare: client_first_bare.to_string(),
+            server_first: server_first.clone(),
+            credential,
+        };
+        Ok((scram, server_first))
+    }
+
+    /// ## Summary
+    /// クライアントの最後のメッセージ(ClientProof)を検証する
+    ///
+    /// ## Returns
+    /// サーバーの最後のメッセージ(ServerSignature)
+    pub fn finish(&self, client_final: &str) -> Result<String> {
+        let Some((without_proof, proof)) = client_final.rsplit_once(",p=") else {
+            bail!("ClientProofがありません");
+        };
+        let mut channel_binding = None;
+        let mut nonce = None;
+        for attribute in without_proof.split(',') {
+            if let Some(value) = attribute.strip_prefix("c=") {
+                channel_binding = Some(value);
+            } else if let Some(value) = attribute.strip_prefix("r=") {
+                nonce = Some(value);
+            }
+        }
+        let expected_binding = general_purpose::STANDARD.encode(&self.gs2_header);
+        if channel_binding != Some(expected_binding.as_str()) {
+            bail!("チャネルバインディングが一致しません");
+        }
+        if nonce != Some(self.nonce.as_str()) {
+            bail!("nonceが一致しません");
+        }
+        let Some(credential) = &self.credential else {
+            bail!("SCRAMで認証できないユーザーです: {}", &self.username);
+        };
+
+        let proof = general_purpose::STANDARD
+            .decode(proof)
+            .context("ClientProofが不正です")?;
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, without_proof
+        );
+        let client_signature = hmac_sha256(&credential.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            bail!("ClientProofの長さが不正です");
+        }
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(&client_signature)
+            .map(|(p, s)| p ^ s)
+            .collect();
+        if !constant_time_eq(&Sha256::digest(&client_key), &credential.stored_key) {
+            bail!("ClientProofが一致しません");
+        }
+
+        let server_signature = hmac_sha256(&credential.server_key, auth_message.as_bytes());
+        Ok(format!(
+            "v={}",
+            general_purpose::STANDARD.encode(server_signature)
+        ))
+    }
+
+    pub fn get_username(&self) -> &str {
+        &self.username
+    }
+}
+
+/// GS2ヘッダー(チャネルバインディングなし)のユーザー名をエスケープを解除して取得する
+fn decode_saslname(value: &str) -> Result<String> {
+    if value.replace("=2C", "").replace("=3D", "").contains('=') {
+        bail!("ユーザー名のエスケープが不正です: {}", value);
+    }
+    Ok(value.replace("=2C", ",").replace("=3D", "="))
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMACは任意の長さの鍵を受け付ける");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// ソルト用のランダムなバイト列
+fn random_bytes() -> Vec<u8> {
+    Uuid::new_v4().as_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_NONCE: &str = "rOprNGfwEbeRWgbNEkqO";
+    const NONCE: &str = "rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+    const SALT: &str = "W22ZaJ0SNY7soEsUEjb6gQ==";
+
+    /// RFC 7677の例と同じnonceでやり取りを始めた状態
+    fn rfc7677_exchange() -> ScramSha256 {
+        let salt = general_purpose::STANDARD.decode(SALT).unwrap();
+        ScramSha256 {
+            username: "user".to_string(),
+            gs2_header: "n,,".to_string(),
+            nonce: NONCE.to_string(),
+            client_first_bare: format!("n=user,r={}", CLIENT_NONCE),
+            server_first: format!("r={},s={},i=4096", NONCE, SALT),
+            credential: Some(ScramCredential::derive("pencil", &salt, 4096)),
+        }
+    }
+
+    /// クライアント側でClientProofを計算する
+    fn client_proof(password: &str, salt: &[u8], iterations: u32, auth_message: &str) -> String {
+        let credential = ScramCredential::derive(password, salt, iterations);
+        let mut salted_password = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut salted_password);
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let client_signature = hmac_sha256(&credential.stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(&client_signature)
+            .map(|(k, s)| k ^ s)
+            .collect();
+        general_purpose::STANDARD.encode(proof)
+    }
+
+    #[test]
+    fn verifies_rfc7677_example() {
+        let scram = rfc7677_exchange();
+        let client_final = format!(
+            "c=biws,r={},p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+            NONCE
+        );
+        assert_eq!(
+            scram.finish(&client_final).unwrap(),
+            "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_proof_and_nonce() {
+        let scram = rfc7677_exchange();
+        let wrong_proof = format!(
+            "c=biws,r={},p={}",
+            NONCE,
+            general_purpose::STANDARD.encode([0u8; 32])
+        );
+        assert!(scram.finish(&wrong_proof).is_err());
+        let wrong_nonce = format!(
+            "c=biws,r={},p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+            CLIENT_NONCE
+        );
+        assert!(scram.finish(&wrong_nonce).is_err());
+        assert!(scram.finish("c=biws").is_err());
+    }
+
+    #[test]
+    fn authenticates_user_from_database() {
+        let users = UserDatabase::parse("user:{PLAIN}pencil\n").unwrap();
+        let (scram, server_first) = ScramSha256::start("n,,n=user,r=abc", &users).unwrap();
+        assert_eq!(scram.get_username(), "user");
+
+        let mut nonce = "";
+        let mut salt = vec![];
+        let mut iterations = 0;
+        for attribute in server_first.split(',') {
+            if let Some(value) = attribute.strip_prefix("r=") {
+                nonce = value;
+            } else if let Some(value) = attribute.strip_prefix("s=") {
+                salt = general_purpose::STANDARD.decode(value).unwrap();
+            } else if let Some(value) = attribute.strip_prefix("i=") {
+                iterations = value.parse().unwrap();
+            }
+        }
+        assert!(nonce.starts_with("abc") && nonce.len() > 3);
+
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!("n=user,r=abc,{},{}", server_first, without_proof);
+        let proof = client_proof("pencil", &salt, iterations, &auth_message);
+        assert!(scram
+            .finish(&format!("{},p={}", without_proof, proof))
+            .is_ok());
+        let proof = client_proof("wrong", &salt, iterations, &auth_message);
+        assert!(scram
+            .finish(&format!("{},p={}", without_proof, proof))
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_client_first() {
+        let users = UserDatabase::parse("user:{PLAIN}pencil\n").unwrap();
+        assert!(ScramSha256::start("p=tls-unique,,n=user,r=abc", &users).is_err());
+        assert!(ScramSha256::start("n,a=admin,n=user,r=abc", &users).is_err());
+        assert!(ScramSha256::start("n,,n=user", &users).is_err());
+        assert!(ScramSha256::start("n,,m=ext,n=user,r=abc", &users).is_err());
+        // 存在しないユーザーでも最初のメッセージは返す(最後に失敗する)
+        let (scram, _) = ScramSha256::start("n,,n=nobody,r=abc", &users).unwrap();
+        assert!(scram.credential.is_none());
+    }
+
+    #[test]
+    fn decodes_saslname() {
+        assert_eq!(decode_saslname("a=2Cb=3Dc").unwrap(), "a,b=c");
+        assert!(decode_saslname("a=2Db").is_err());
+    }
+
+    #[test]
+    fn credential_round_trips() {
+        let salt = general_purpose::STANDARD.decode(SALT).unwrap();
+        let credential = ScramCredential::derive("pencil", &salt, 4096);
+        let parsed = ScramCredential::parse(&credential.to_string()).unwrap();
+        assert!(parsed.verify_password("pencil"));
+        assert!(!parsed.verify_password("wrong"));
+        assert!(ScramCredential::parse("4096,AAAA").is_err());
+        assert!(ScramCredential::parse("many,AAAA,AAAA,AAAA").is_err());
+        assert!(ScramCredential::parse("4096,!,AAAA,AAAA").is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Context, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use log::{info, warn};
+
+use super::{cram_md5, scram::ScramCredential};
+
+const SCHEME_PLAIN: &str = "{PLAIN}";
+const SCHEME_ARGON2ID: &str = "{ARGON2ID}";
+const SCHEME_SCRAM_SHA256: &str = "{SCRAM-SHA-256}";
+
+/// ユーザーごとに保存しているパスワード
+enum Credential {
+    /// 平文(開発用)
+    Plain(String),
+    /// Argon2のPHC文字列
+    Argon2(String),
+    /// SCRAM-SHA-256の検証値
+    ScramSha256(ScramCredential),
+}
+
+/// SMTP認証に使うユーザーデータベース
+///
+/// 1行に1ユーザー、`ユーザー名:{SCHEME}値` の形式で記述する(`#` で始まる行はコメント)
+///
+/// ```text
+/// alice:{ARGON2ID}$argon2id$v=19$m=19456,t=2,p=1$...
+/// bob:{PLAIN}password
+/// carol:{SCRAM-SHA-256}4096,<salt>,<StoredKey>,<ServerKey>
+/// ```
+///
+/// CRAM-MD5は平文のパスワードが必要なため `{PLAIN}` のユーザーのみ、
+/// SCRAM-SHA-256は `{PLAIN}` と `{SCRAM-SHA-256}` のユーザーが認証できる
+pub struct UserDatabase {
+    users: HashMap<String, Credential>,
+}
+
+impl UserDatabase {
+    /// ## Summary
+    /// ユーザーデータベースのファイルを読み込む
+    ///
+    /// ## Parameters
+    /// - `path`: ファイルのパス
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("ユーザーデータベース {:?} を読み込めません", path))?;
+        let database = Self::parse(&content)?;
+        info!(
+            "ユーザーデータベース {:?} から {} 件のユーザーを読み込みました",
+            path,
+            database.users.len()
+        );
+        Ok(database)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut users = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((username, value)) = line.split_once(':') else {
+                bail!("{}行目: ユーザー名とパスワードは ':' で区切ってください", i + 1);
+            };
+            let credential = if let Some(password) = value.strip_prefix(SCHEME_PLAIN) {
+                Credential::Plain(password.to_string())
+            } else if let Some(value) = value.strip_prefix(SCHEME_SCRAM_SHA256) {
+                match ScramCredential::parse(value) {
+                    Ok(credential) => Credential::ScramSha256(credential),
+                    Err(e) => bail!("{}行目: {}", i + 1, e),
+                }
+            } else {
+                // スキーム省略時はPHC文字列とみなす
+                let phc = value.strip_prefix(SCHEME_ARGON2ID).unwrap_or(value);
+                if let Err(e) = PasswordHash::new(phc) {
+                    bail!("{}行目: パスワードハッシュが不正です: {}", i + 1, e);
+                }
+                Credential::Argon2(phc.to_string())
+            };
+            users.insert(username.to_string(), credential);
+        }
+
+        Ok(Self { users })
+    }
+
+    /// ## Summary
+    /// ユーザー名とパスワードが一致するか検証する
+    ///
+    /// ## Returns
+    /// 一致すればtrue(ユーザーが存在しない場合もfalse)
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        match self.users.get(username) {
+            Some(Credential::Plain(expected)) => {
+                constant_time_eq(expected.as_bytes(), password.as_bytes())
+            }
+            Some(Credential::Argon2(phc)) => PasswordHash::new(phc).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            }),
+            Some(Credential::ScramSha256(credential)) => credential.verify_password(password),
+            None => false,
+        }
+    }
+
+    /// ## Summary
+    /// CRAM-MD5の応答を検証する
+    ///
+    /// ## Parameters
+    /// - `username`: ユーザー名
+    /// - `challenge`: サーバーが送ったチャレンジ
+    /// - `digest`: クライアントが返したダイジェスト(16進)
+    pub fn verify_cram_md5(&self, username: &str, challenge: &str, digest: &str) -> bool {
+        match self.users.get(username) {
+            Some(Credential::Plain(password)) => {
+                let expected = cram_md5::digest(password, challenge);
+                constant_time_eq(expected.as_bytes(), digest.to_lowercase().as_bytes())
+            }
+            Some(_) => {
+                warn!("CRAM-MD5は{{PLAIN}}以外のユーザーには使えません username:{}", username);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// ## Summary
+    /// CRAM-MD5で認証できるユーザー(`{PLAIN}`)がいるかどうか
+    pub fn supports_cram_md5(&self) -> bool {
+        self.users
+            .values()
+            .any(|credential| matches!(credential, Credential::Plain(_)))
+    }
+
+    /// ## Summary
+    /// SCRAM-SHA-256の検証値を取得する
+    ///
+    /// ## Note
+    /// `{PLAIN}` のユーザーはランダムなソルトで都度導出する
+    ///
+    /// ## Returns
+    /// ユーザーが存在しない、またはArgon2のユーザーの場合はNone
+    pub fn scram_credential(&self, username: &str) -> Option<ScramCredential> {
+        match self.users.get(username) {
+            Some(Credential::Plain(password)) => Some(ScramCredential::generate(password)),
+            Some(Credential::ScramSha256(credential)) => Some(credential.clone()),
+            Some(Credential::Argon2(_)) => {
+                warn!("SCRAM-SHA-256はArgon2のユーザーには使えません username:{}", username);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// ## Summary
+/// ユーザーデータベースに書き込むパスワードハッシュを生成する
+///
+/// ## Returns
+/// `{ARGON2ID}$argon2id$...` 形式の文字列
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    Ok(format!("{}{}", SCHEME_ARGON2ID, hash))
+}
+
+/// ## Summary
+/// ユーザーデータベースに書き込むSCRAM-SHA-256の検証値を生成する
+///
+/// ## Returns
+/// `{SCRAM-SHA-256}反復回数,salt,StoredKey,ServerKey` 形式の文字列
+pub fn hash_scram_password(password: &str) -> String {
+    format!("{}{}", SCHEME_SCRAM_SHA256, ScramCredential::generate(password))
+}
+
+/// 比較にかかる時間が内容によって変わらないバイト列の比較
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_all_schemes() {
+        let argon2 = hash_password("alice-pw").unwrap();
+        let phc = argon2.strip_prefix(SCHEME_ARGON2ID).unwrap();
+        let content = format!(
+            "# comment\n\nalice:{}\nbob:{{PLAIN}}bob:pw\ncarol:{}\ndave:{}\n",
+            argon2,
+            hash_scram_password("carol-pw"),
+            phc
+        );
+        let users = UserDatabase::parse(&content).unwrap();
+        assert_eq!(users.users.len(), 4);
+
+        assert!(users.verify("alice", "alice-pw"));
+        assert!(!users.verify("alice", "wrong"));
+        // パスワードには `:` を含められる
+        assert!(users.verify("bob", "bob:pw"));
+        assert!(users.verify("carol", "carol-pw"));
+        assert!(!users.verify("carol", "wrong"));
+        // スキームを省略したPHC文字列はArgon2とみなす
+        assert!(users.verify("dave", "alice-pw"));
+        assert!(!users.verify("nobody", "alice-pw"));
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        let Err(error) = UserDatabase::parse("# users\nalice") else {
+            panic!("区切りのない行を受け付けた");
+        };
+        assert!(error.to_string().starts_with("2行目"), "{}", error);
+        assert!(UserDatabase::parse("alice:{ARGON2ID}not-a-hash").is_err());
+        assert!(UserDatabase::parse("alice:not-a-hash").is_err());
+        assert!(UserDatabase::parse("alice:{SCRAM-SHA-256}4096,AAAA").is_err());
+    }
+
+    #[test]
+    fn cram_md5_and_scram_depend_on_scheme() {
+        let content = format!(
+            "alice:{{PLAIN}}pw\ncarol:{}\ndave:$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHQ$dGVzdHRlc3R0ZXN0dGVzdA\n",
+            hash_scram_password("pw")
+        );
+        let users = UserDatabase::parse(&content).unwrap();
+        let digest = cram_md5::digest("pw", "<1@host>");
+        assert!(users.verify_cram_md5("alice", "<1@host>", &digest));
+        assert!(users.verify_cram_md5("alice", "<1@host>", &digest.to_uppercase()));
+        assert!(!users.verify_cram_md5("alice", "<2@host>", &digest));
+        assert!(!users.verify_cram_md5("carol", "<1@host>", &digest));
+        assert!(users.supports_cram_md5());
+        let scram_only = format!("carol:{}\n", hash_scram_password("pw"));
+        assert!(!UserDatabase::parse(&scram_only)
+            .unwrap()
+            .supports_cram_md5());
+
+        assert!(users.scram_credential("alice").is_some());
+        assert!(users.scram_credential("carol").is_some());
+        assert!(users.scram_credential("dave").is_none());
+        assert!(users.scram_credential("nobody").is_none());
+    }
+
+    #[test]
+    fn compares_in_constant_time() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+    }
+}
@@ -0,0 +1,193 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+
+use crate::config::{FLAG_KEYS, SETTING_KEYS};
+
+pub const USAGE: &str = "usage:
+  rust-mail-server [options] [--import <file.mbox|file.eml>]...
+  rust-mail-server --maildir <dir> [options] export <mbox|maildir> <output> [--query <text>]
+  rust-mail-server hash-password [--scram] <password>
+
+options:
+  --config <file.toml>
+  --smtp-addr <addr>  --smtps-addr <addr>  --max-message-size <bytes>
+  --http-addr <addr>  --index-html <file>  --admin-token <token>
+  --tls-cert <file>  --tls-key <file>
+  --tls-self-signed  --tls-hostnames <host,...>  --tls-save-generated
+  --tls-watch-interval <seconds>
+  --tls-client-ca <file>  --tls-require-client-cert
+  --users-file <file>  --require-auth  --require-tls  --client-cert-auth
+  --oauth-tokens-file <file>  --oauth-jwt-secret <secret>
+  --maildir <dir>";
+
+/// コマンドライン引数の解析結果
+pub struct Args {
+    pub mode: RunMode,
+    /// `--config` で指定された設定ファイル
+    pub config_path: Option<PathBuf>,
+    /// `--smtp-addr` 等で指定された設定の上書き `(項目, 値)`
+    pub overrides: Vec<(String, String)>,
+}
+
+/// 起動モード
+pub enum RunMode {
+    /// サーバーを起動する(起動前に指定したファイルのメールを取り込む)
+    Serve { imports: Vec<PathBuf> },
+    /// ストアのメールをファイルに書き出して終了する
+    Export {
+        format: ExportFormat,
+        output: PathBuf,
+        query: Option<String>,
+    },
+    /// ユーザーデータベース用のパスワードハッシュを出力して終了する
+    /// (scramがtrueならArgon2の代わりにSCRAM-SHA-256の検証値)
+    HashPassword { password: String, scram: bool },
+}
+
+pub enum ExportFormat {
+    Mbox,
+    Maildir,
+}
+
+/// ## Summary
+/// コマンドライン引数から起動モードと設定の上書きを取得する
+///
+/// ## Note
+/// 設定の引数(`--config` / `--smtp-addr` 等)はどの位置に書いてもよい
+///
+/// ## Parameters
+/// - `args`: プログラム名を除いた引数
+pub fn parse_args(args: impl Iterator<Item = String>) -> Result<Args> {
+    let mut config_path = None;
+    let mut overrides = vec![];
+    let mut rest = vec![];
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(key) = arg.strip_prefix("--") else {
+            rest.push(arg);
+            continue;
+        };
+        if key == "config" {
+            match args.next() {
+                Some(path) => config_path = Some(path.into()),
+                None => bail!("{}", USAGE),
+            }
+        } else if FLAG_KEYS.contains(&key) {
+            overrides.push((key.to_string(), "true".to_string()));
+        } else if SETTING_KEYS.contains(&key) {
+            match args.next() {
+                Some(value) => overrides.push((key.to_string(), value)),
+                None => bail!("--{} に値がありません\n{}", key, USAGE),
+            }
+        } else {
+            rest.push(arg);
+        }
+    }
+
+    Ok(Args {
+        mode: parse_mode(rest.into_iter())?,
+        config_path,
+        overrides,
+    })
+}
+
+/// 設定の引数を除いた引数から起動モードを取得する
+fn parse_mode(args: impl Iterator<Item = String>) -> Result<RunMode> {
+    let mut args = args.peekable();
+
+    if args.peek().map(|arg| arg.as_str()) == Some("export") {
+        args.next();
+        let format = match args.next().as_deref() {
+            Some("mbox") => ExportFormat::Mbox,
+            Some("maildir") => ExportFormat::Maildir,
+            _ => bail!("{}", USAGE),
+        };
+        let Some(output) = args.next() else {
+            bail!("{}", USAGE);
+        };
+        let mut query = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--query" | "-q" => query = args.next(),
+                _ => bail!("unknown argument: {}\n{}", arg, USAGE),
+            }
+        }
+        return Ok(RunMode::Export {
+            format,
+            output: output.into(),
+            query,
+        });
+    }
+
+    if args.peek().map(|arg| arg.as_str()) == Some("hash-password") {
+        args.next();
+        let scram = args.peek().map(|arg| arg.as_str()) == Some("--scram");
+        if scram {
+            args.next();
+        }
+        let Some(password) = args.next() else {
+            bail!("{}", USAGE);
+        };
+        return Ok(RunMode::HashPassword { password, scram });
+    }
+
+    let mut imports = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--import" => match args.next() {
+                Some(path) => imports.push(path.into()),
+                None => bail!("{}", USAGE),
+            },
+            _ => bail!("unknown argument: {}\n{}", arg, USAGE),
+        }
+    }
+    Ok(RunMode::Serve { imports })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn accepts_settings_anywhere() {
+        let args = parse(&[
+            "--require-auth",
+            "export",
+            "mbox",
+            "out.mbox",
+            "--smtp-addr",
+            "0.0.0.0:25",
+            "--query",
+            "hello",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.overrides,
+            [
+                ("require-auth".to_string(), "true".to_string()),
+                ("smtp-addr".to_string(), "0.0.0.0:25".to_string()),
+            ]
+        );
+        let RunMode::Export { output, query, .. } = args.mode else {
+            panic!("exportとして解析されなかった");
+        };
+        assert_eq!(output, PathBuf::from("out.mbox"));
+        assert_eq!(query.as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn rejects_missing_values() {
+        assert!(parse(&["--config"]).is_err());
+        assert!(parse(&["--smtp-addr"]).is_err());
+        assert!(parse(&["--unknown"]).is_err());
+        let args = parse(&["--config", "server.toml"]).unwrap();
+        assert_eq!(args.config_path, Some(PathBuf::from("server.toml")));
+        assert!(matches!(args.mode, RunMode::Serve { imports } if imports.is_empty()));
+    }
+}
@@ -8,10 +8,17 @@ pub enum Command {
     MailFrom,
     RcptTo,
     Data,
+    Bdat,
     Quit,
     AuthPlain,
     AuthLogin,
+    AuthCramMd5,
+    AuthScramSha256,
+    AuthXOAuth2,
+    AuthOAuthBearer,
     StartTls,
+    Rset,
+    Noop,
     Unknown,
 }
 
@@ -54,21 +61,42 @@ pub fn get_command_from_str(input: &str) -> Command {
     } else if input.starts_with(DATA) {
         debug!("command is {}", input);
         return Data;
-    } else if input.starts_with(QUIT) {
+    } else if input.starts_with(BDAT) {
         debug!("command is {}", input);
-        return Quit;
+        return Bdat;
     } else if input.starts_with(QUIT) {
         debug!("command is {}", input);
-        return Data;
+        return Quit;
     } else if input.starts_with(AUTH_PLAIN) {
-        debug!("command is {}", input);
+        // 資格情報を含むのでコマンド名のみ出力する
+        debug!("command is {}", AUTH_PLAIN);
         return AuthPlain;
     } else if input.starts_with(AUTH_LOGIN) {
         debug!("command is {}", input);
         return AuthLogin;
+    } else if input.starts_with(AUTH_CRAM_MD5) {
+        debug!("command is {}", input);
+        return AuthCramMd5;
+    } else if input.starts_with(AUTH_SCRAM_SHA256) {
+        // 初期応答にユーザー名を含むのでコマンド名のみ出力する
+        debug!("command is {}", AUTH_SCRAM_SHA256);
+        return AuthScramSha256;
+    } else if input.starts_with(AUTH_XOAUTH2) {
+        // トークンを含むのでコマンド名のみ出力する
+        debug!("command is {}", AUTH_XOAUTH2);
+        return AuthXOAuth2;
+    } else if input.starts_with(AUTH_OAUTHBEARER) {
+        debug!("command is {}", AUTH_OAUTHBEARER);
+        return AuthOAuthBearer;
     } else if input.starts_with(STARTTLS) {
         debug!("command is {}", input);
         return StartTls;
+    } else if input.starts_with(RSET) {
+        debug!("command is {}", input);
+        return Rset;
+    } else if input.starts_with(NOOP) {
+        debug!("command is {}", input);
+        return Noop;
     }
 
     debug!("command is {} [Unknown]", input);
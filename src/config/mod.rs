@@ -0,0 +1,336 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use rumbok::Getter;
+use serde::Deserialize;
+
+use crate::constants::MAX_MESSAGE_SIZE;
+
+pub mod tls;
+
+/// 設定ファイルのパスを指定する環境変数
+const CONFIG_ENV: &str = "RUST_MAIL_CONFIG";
+/// 環境変数で設定を上書きする場合の接頭辞(`smtp-addr` → `RUST_MAIL_SMTP_ADDR`)
+const ENV_PREFIX: &str = "RUST_MAIL_";
+
+/// 環境変数・コマンドライン引数(`--smtp-addr 0.0.0.0:2525` 等)で上書きできる設定項目
+pub const SETTING_KEYS: [&str; 21] = [
+    "smtp-addr",
+    "smtps-addr",
+    "max-message-size",
+    "http-addr",
+    "index-html",
+    "admin-token",
+    "tls-cert",
+    "tls-key",
+    "tls-self-signed",
+    "tls-hostnames",
+    "tls-save-generated",
+    "tls-watch-interval",
+    "tls-client-ca",
+    "tls-require-client-cert",
+    "users-file",
+    "require-auth",
+    "require-tls",
+    "client-cert-auth",
+    "oauth-tokens-file",
+    "oauth-jwt-secret",
+    "maildir",
+];
+
+/// 値を取らない(指定すればtrueになる)コマンドライン引数
+pub const FLAG_KEYS: [&str; 6] = [
+    "require-auth",
+    "require-tls",
+    "client-cert-auth",
+    "tls-self-signed",
+    "tls-save-generated",
+    "tls-require-client-cert",
+];
+
+/// ## Summary
+/// サーバー全体の設定
+///
+/// ## Note
+/// 既定値 < 設定ファイル(TOML) < 環境変数 < コマンドライン引数 の順に上書きする
+///
+/// ```toml
+/// [smtp]
+/// addr = "0.0.0.0:2525"
+/// smtps_addr = "0.0.0.0:4650"
+/// max_message_size = 26214400
+///
+/// [http]
+/// addr = "0.0.0.0:8025"
+/// index_html = "static/index.html"
+/// admin_token = "secret"
+///
+/// [tls]
+/// cert = "cert.pem"
+/// key = "key.pem"
+/// self_signed = true
+/// hostnames = ["localhost", "127.0.0.1", "mail.test"]
+/// save_generated = false
+/// watch_interval = 10
+/// client_ca = "client-ca.pem"
+/// require_client_cert = false
+///
+/// [auth]
+/// users_file = "users.txt"
+/// require_auth = false
+/// require_tls = false
+/// client_cert_auth = false
+///
+/// [storage]
+/// maildir = "./mails"
+/// ```
+#[derive(Debug, Default, Deserialize, Getter)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    smtp: SmtpConfig,
+    http: HttpConfig,
+    tls: TlsConfig,
+    auth: AuthConfig,
+    storage: StorageConfig,
+}
+
+#[derive(Debug, Deserialize, Getter)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    /// SMTPの待ち受けアドレス
+    addr: String,
+    /// SMTPS(暗黙TLS)の待ち受けアドレス(未指定なら起動しない)
+    smtps_addr: Option<String>,
+    /// 受け付けるメッセージサイズの上限(バイト)
+    max_message_size: usize,
+}
+
+#[derive(Debug, Deserialize, Getter)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Web UI / APIの待ち受けアドレス
+    addr: String,
+    /// Web UIのHTMLファイル
+    index_html: PathBuf,
+    /// 管理用API(`POST /api/tls/reload`)の `Authorization: Bearer` トークン
+    /// (未指定ならループバックアドレスからのリクエストのみ受け付ける)
+    admin_token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Getter)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// サーバー証明書(PEM)
+    cert: PathBuf,
+    /// 秘密鍵(PEM)
+    key: PathBuf,
+    /// 証明書ファイルがない場合に自己署名証明書(テスト用CAで署名)を生成する
+    self_signed: bool,
+    /// 生成する証明書のホスト名(IPアドレスも可)
+    hostnames: Vec<String>,
+    /// 生成した証明書を `cert` / `key` のパスに保存する(未指定ならメモリー上のみ)
+    save_generated: bool,
+    /// 証明書ファイルの更新を確認する間隔(秒、0なら確認しない)
+    watch_interval: u64,
+    /// クライアント証明書を検証するCA証明書(PEM、未指定ならクライアント証明書を要求しない)
+    client_ca: Option<PathBuf>,
+    /// クライアント証明書のない接続を拒否する(未指定なら証明書なしの接続も受け付ける)
+    require_client_cert: bool,
+}
+
+#[derive(Debug, Default, Deserialize, Getter)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// SMTP認証のユーザーデータベース(未指定なら認証は常に成功)
+    users_file: Option<PathBuf>,
+    /// MAIL FROMの前に認証を必須にする
+    require_auth: bool,
+    /// AUTH・MAIL FROMの前にSTARTTLSを必須にする
+    require_tls: bool,
+    /// 検証済みのクライアント証明書で接続したセッションを認証済みとして扱う
+    client_cert_auth: bool,
+    /// XOAUTH2 / OAUTHBEARERで受け付けるトークンの一覧
+    oauth_tokens_file: Option<PathBuf>,
+    /// XOAUTH2 / OAUTHBEARERで受け付けるJWT(HS256)の署名鍵
+    oauth_jwt_secret: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Getter)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Maildirの保存先(未指定ならメモリー上に保存)
+    maildir: Option<PathBuf>,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:2525".to_string(),
+            smtps_addr: None,
+            max_message_size: MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:8025".to_string(),
+            index_html: PathBuf::from("static/index.html"),
+            admin_token: None,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: PathBuf::from("cert.pem"),
+            key: PathBuf::from("key.pem"),
+            self_signed: false,
+            hostnames: vec!["localhost".to_string(), "127.0.0.1".to_string()],
+            save_generated: false,
+            watch_interval: 0,
+            client_ca: None,
+            require_client_cert: false,
+        }
+    }
+}
+
+impl Config {
+    /// ## Summary
+    /// 設定ファイル・環境変数・コマンドライン引数から設定を読み込む
+    ///
+    /// ## Parameters
+    /// - `config_path`: 設定ファイルのパス(Noneなら環境変数 `RUST_MAIL_CONFIG`、それもなければ既定値)
+    /// - `overrides`: コマンドライン引数で指定された `(項目, 値)`
+    pub fn load(config_path: Option<&Path>, overrides: &[(String, String)]) -> Result<Self> {
+        let env_path = std::env::var(CONFIG_ENV).ok().map(PathBuf::from);
+        let mut config = match config_path.or(env_path.as_deref()) {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .with_context(|| format!("設定ファイル {:?} を読み込めません", path))?;
+                let config = toml::from_str(&content)
+                    .with_context(|| format!("設定ファイル {:?} が不正です", path))?;
+                info!("設定ファイル {:?} を読み込みました", path);
+                config
+            }
+            None => Self::default(),
+        };
+
+        for key in SETTING_KEYS {
+            let env_key = format!("{}{}", ENV_PREFIX, key.to_uppercase().replace('-', "_"));
+            if let Ok(value) = std::env::var(&env_key) {
+                config
+                    .set(key, &value)
+                    .with_context(|| format!("環境変数 {} が不正です", env_key))?;
+            }
+        }
+        for (key, value) in overrides {
+            config
+                .set(key, value)
+                .with_context(|| format!("引数 --{} が不正です", key))?;
+        }
+
+        Ok(config)
+    }
+
+    /// ## Summary
+    /// 設定項目を1つ上書きする
+    ///
+    /// ## Parameters
+    /// - `key`: `SETTING_KEYS` のいずれか
+    /// - `value`: 値(文字列)
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "smtp-addr" => self.smtp.addr = value.to_string(),
+            "smtps-addr" => self.smtp.smtps_addr = Some(value.to_string()),
+            "max-message-size" => {
+                self.smtp.max_message_size = value
+                    .parse()
+                    .with_context(|| format!("数値ではありません: {}", value))?
+            }
+            "http-addr" => self.http.addr = value.to_string(),
+            "index-html" => self.http.index_html = value.into(),
+            "admin-token" => self.http.admin_token = Some(value.to_string()),
+            "tls-cert" => self.tls.cert = value.into(),
+            "tls-key" => self.tls.key = value.into(),
+            "tls-self-signed" => self.tls.self_signed = parse_bool(value)?,
+            // カンマ区切りで複数指定する
+            "tls-hostnames" => {
+                self.tls.hostnames = value
+                    .split(',')
+                    .map(|hostname| hostname.trim().to_string())
+                    .filter(|hostname| !hostname.is_empty())
+                    .collect()
+            }
+            "tls-save-generated" => self.tls.save_generated = parse_bool(value)?,
+            "tls-watch-interval" => {
+                self.tls.watch_interval = value
+                    .parse()
+                    .with_context(|| format!("数値ではありません: {}", value))?
+            }
+            "tls-client-ca" => self.tls.client_ca = Some(value.into()),
+            "tls-require-client-cert" => self.tls.require_client_cert = parse_bool(value)?,
+            "users-file" => self.auth.users_file = Some(value.into()),
+            "require-auth" => self.auth.require_auth = parse_bool(value)?,
+            "require-tls" => self.auth.require_tls = parse_bool(value)?,
+            "client-cert-auth" => self.auth.client_cert_auth = parse_bool(value)?,
+            "oauth-tokens-file" => self.auth.oauth_tokens_file = Some(value.into()),
+            "oauth-jwt-secret" => self.auth.oauth_jwt_secret = Some(value.to_string()),
+            "maildir" => self.storage.maildir = Some(value.into()),
+            _ => bail!("不明な設定項目です: {}", key),
+        }
+        Ok(())
+    }
+}
+
+/// "1" / "true" / "yes" / "on" をtrue、"0" / "false" / "no" / "off" をfalseとみなす
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => bail!("true / false を指定してください: {}", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_file_with_arguments() {
+        let path = std::env::temp_dir().join(format!(
+            "rust-mail-server-config-{}.toml",
+            uuid::Uuid::new_v4()
+        ));
+        std::fs::write(
+            &path,
+            "[smtp]\naddr = \"0.0.0.0:25\"\nmax_message_size = 1024\n\n[auth]\nrequire_auth = true\n",
+        )
+        .unwrap();
+        let overrides = [("smtp-addr".to_string(), "127.0.0.1:2526".to_string())];
+        let config = Config::load(Some(&path), &overrides).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.get_smtp().get_addr(), "127.0.0.1:2526");
+        assert_eq!(*config.get_smtp().get_max_message_size(), 1024);
+        assert!(*config.get_auth().get_require_auth());
+        // 指定していない項目は既定値
+        assert_eq!(config.get_http().get_addr(), "127.0.0.1:8025");
+        assert!(config.get_storage().get_maildir().is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(toml::from_str::<Config>("[smtp]\nport = 25\n").is_err());
+
+        let mut config = Config::default();
+        assert!(config.set("max-message-size", "large").is_err());
+        assert!(config.set("require-tls", "maybe").is_err());
+        assert!(config.set("unknown", "value").is_err());
+        config.set("require-tls", "on").unwrap();
+        assert!(*config.get_auth().get_require_tls());
+    }
+}
@@ -5,15 +5,45 @@ pub const RCPTTO: &str = "RCPT TO:";
 pub const DATA: &str = "DATA";
 pub const QUIT: &str = "QUIT";
 pub const STARTTLS: &str = "STARTTLS";
+pub const RSET: &str = "RSET";
+pub const NOOP: &str = "NOOP";
+pub const BDAT: &str = "BDAT";
 pub const AUTH_PLAIN: &str = "AUTH PLAIN";
 pub const AUTH_LOGIN: &str = "AUTH LOGIN";
+pub const AUTH_CRAM_MD5: &str = "AUTH CRAM-MD5";
+pub const AUTH_SCRAM_SHA256: &str = "AUTH SCRAM-SHA-256";
+pub const AUTH_XOAUTH2: &str = "AUTH XOAUTH2";
+pub const AUTH_OAUTHBEARER: &str = "AUTH OAUTHBEARER";
 pub const TEXT_PLAIN: &str = "text/plain";
 pub const TEXT_HTML: &str = "text/html";
 
+// ESMTP拡張
+pub const EXT_8BITMIME: &str = "8BITMIME";
+pub const EXT_SMTPUTF8: &str = "SMTPUTF8";
+pub const EXT_PIPELINING: &str = "PIPELINING";
+pub const EXT_CHUNKING: &str = "CHUNKING";
+
+/// 受け付けるメッセージサイズの上限(EHLOのSIZEで通知する)
+pub const MAX_MESSAGE_SIZE: usize = 25 * 1024 * 1024;
+
 // Message byte
+pub const GREETING_MESSAGE_BYTES: &[u8] = b"220 Rust SMTP Server Ready\r\n";
 pub const AUTH_REQUIRED_MESSAGE_BYTES: &[u8] = b"530 Authentication required\r\n";
+/// TLS必須の設定で平文のままAUTH・MAIL FROMを受け取った場合の応答(RFC 4954の538)
+pub const ENCRYPTION_REQUIRED_MESSAGE_BYTES: &[u8] =
+    b"538 Encryption required, issue a STARTTLS command first\r\n";
 pub const OK_MESSAGE_BYTES: &[u8] = b"250 Ok\r\n";
 pub const STARTTLS_MESSAGE_BYTES: &[u8] = b"220 Ready to start TLS\r\n";
 pub const STARTTLS_NO_SUPPORTED_MESSAGE_BYTES: &[u8] = b"500 STARTTLS not supported\r\n";
 pub const INVALID_BASE64_MESSAGE_BYTES: &[u8] = b"501 Invalid base64 encoding\r\n";
-pub const AUTH_SUCCESS_MESSAGE_BYTES: &[u8] = b"235 Authentication successful\r\n";
+pub const AUTH_MECHANISM_UNSUPPORTED_MESSAGE_BYTES: &[u8] =
+    b"504 Unrecognized authentication type\r\n";
+pub const AUTH_CANCELED_MESSAGE_BYTES: &[u8] = b"501 Authentication canceled\r\n";
+pub const LOCAL_ERROR_MESSAGE_BYTES: &[u8] = b"451 Requested action aborted: local error in processing\r\n";
+pub const BAD_SEQUENCE_MESSAGE_BYTES: &[u8] = b"503 Bad sequence of commands\r\n";
+pub const TLS_ALREADY_ACTIVE_MESSAGE_BYTES: &[u8] = b"503 TLS already active\r\n";
+pub const MESSAGE_SIZE_EXCEEDED_MESSAGE_BYTES: &[u8] =
+    b"552 Message size exceeds fixed maximum message size\r\n";
+pub const SYNTAX_ERROR_MESSAGE_BYTES: &[u8] = b"501 Syntax error in parameters or arguments\r\n";
+pub const PARAMETERS_NOT_RECOGNIZED_MESSAGE_BYTES: &[u8] =
+    b"555 MAIL FROM/RCPT TO parameters not recognized or not implemented\r\n";
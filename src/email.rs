@@ -1,12 +1,17 @@
 use std::{sync::Arc, usize};
 
 use chrono::{DateTime, Local};
-use log::{debug, error};
-use mailparse::{DispositionType, ParsedMail};
+use log::debug;
+use mailparse::ParsedMail;
 use rumbok::{AllArgsConstructor, Getter};
 use serde::{de::IntoDeserializer, Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::constants::{TEXT_HTML, TEXT_PLAIN};
+use crate::{
+    envelope::Envelope,
+    mail_header::{AddressHeaders, HeaderField},
+    mime::{self, MimeContent, MimePart},
+};
 
 #[derive(Serialize, AllArgsConstructor)]
 pub struct Email {
@@ -16,32 +21,66 @@ pub struct Email {
 
 #[derive(Clone, Debug, Getter)]
 pub struct EmailData {
+    /// 受信時に採番する不変のID(UUID)
+    id: String,
     received_time: DateTime<Local>,
-    raw: String,
+    /// 受信したままのバイト列(8bitの本文もそのまま保持する)
+    raw: Vec<u8>,
+    /// 一覧・検索に使う本文(text/plain、なければHTMLから取り出したテキスト)
     body: String,
+    /// text/plainの本文
+    text: Option<String>,
+    /// text/htmlの本文
+    html: Option<String>,
     subject: Option<String>,
     from: Option<String>,
     to: Option<String>,
+    /// From / To / Cc 等のアドレスヘッダーを解析したもの
+    addresses: AddressHeaders,
+    /// すべてのヘッダー(受信した順)
+    headers: Vec<HeaderField>,
+    /// 入れ子のmultipartを含むすべての添付ファイル
     attachments: Vec<AttachmentData>,
+    /// Content-IDで本文から参照されるインラインのパート(multipart/relatedの画像等)
+    inline_parts: Vec<AttachmentData>,
+    /// MIMEパートの木構造(解析できなかった場合はNone)
+    parts: Option<MimePart>,
+    envelope: Envelope,
 }
 
 #[derive(Serialize)]
 pub struct EmailSummary {
-    id: usize,
+    id: String,
     received_time: String,
     subject: Option<String>,
     from: Option<String>,
     to: Option<String>,
+    // From / Sender / Reply-To / To / Cc / Bcc の表示名とアドレス
+    addresses: AddressHeaders,
+    // SMTPエンベロープ(null senderは空文字)
+    mail_from: Option<String>,
+    rcpt_to: Vec<String>,
+    // 受信時に使われたESMTP拡張
+    extensions: Vec<String>,
+    // 送信したSMTP AUTHのユーザー
+    auth_user: Option<String>,
+    // 送信したクライアント証明書のサブジェクト
+    client_cert_subject: Option<String>,
     // raw データは詳細 API 用に保持
     raw: String,
     attachments: Vec<String>,
     body: String,
+    // text/plain・text/htmlそれぞれの本文
+    text: Option<String>,
+    html: Option<String>,
 }
 
-#[derive(Clone, Debug, Getter)]
+#[derive(Clone, Debug, Getter, AllArgsConstructor)]
 pub struct AttachmentData {
     filename: Option<String>,
     content_type: String,
+    /// Content-ID(`<` `>` は除く、HTMLから `cid:` で参照される)
+    content_id: Option<String>,
     data: Arc<Vec<u8>>,
 }
 
@@ -51,42 +90,97 @@ impl AttachmentData {
     }
 }
 
-#[derive(Deserialize, Getter)]
+#[derive(Deserialize, Getter, AllArgsConstructor)]
 pub struct SearchQuery {
     q: Option<String>,
 }
 
+impl SearchQuery {
+    /// 検索条件(件名・送信者・本文の部分一致)にメールが一致するかどうか
+    pub fn matches(&self, email: &EmailData) -> bool {
+        if let Some(query) = &self.q {
+            let query_lower = query.to_lowercase();
+            return email
+                .get_subject()
+                .as_ref()
+                .is_some_and(|s| s.to_lowercase().contains(&query_lower))
+                || email
+                    .get_from()
+                    .as_ref()
+                    .is_some_and(|s| s.to_lowercase().contains(&query_lower))
+                || email.get_body().to_lowercase().contains(&query_lower);
+        }
+        true
+    }
+}
+
 impl EmailData {
-    pub fn new(mail_content: String, recived_time: DateTime<Local>) -> Self {
-        let parsed = mailparse::parse_mail(mail_content.as_bytes());
+    pub fn new(mail_content: Vec<u8>, envelope: Envelope, recived_time: DateTime<Local>) -> Self {
+        Self::restore(
+            Uuid::new_v4().to_string(),
+            mail_content,
+            envelope,
+            recived_time,
+        )
+    }
 
-        let (subject, from, to, attachments, body) = if let Ok(parsed_mail) = parsed {
-            Self::extract_headers(&parsed_mail)
+    /// 保存済みのメールを復元する(IDは保存時のものを引き継ぐ)
+    pub fn restore(
+        id: String,
+        mail_content: Vec<u8>,
+        envelope: Envelope,
+        recived_time: DateTime<Local>,
+    ) -> Self {
+        let parsed = mailparse::parse_mail(&mail_content);
+
+        let (subject, from, to, addresses, headers, mime) = if let Ok(parsed_mail) = parsed {
+            let (subject, from, to) = Self::extract_headers(&parsed_mail);
+            let addresses = AddressHeaders::from_headers(&parsed_mail.headers);
+            let headers = parsed_mail
+                .headers
+                .iter()
+                .map(HeaderField::from_header)
+                .collect();
+            let mime = MimeContent::parse(&parsed_mail);
+            (subject, from, to, addresses, headers, mime)
         } else {
-            (None, None, None, vec![], "".to_string())
+            (
+                None,
+                None,
+                None,
+                AddressHeaders::default(),
+                vec![],
+                MimeContent::default(),
+            )
         };
+        // 一覧・検索に使う本文(HTMLのタグ等は含めない)
+        let body = mime
+            .text
+            .clone()
+            .or_else(|| mime.html.as_deref().map(mime::html_to_text))
+            .unwrap_or_default();
 
         Self {
+            id,
             received_time: recived_time,
             raw: mail_content,
             subject: subject,
             from: from,
             to: to,
-            attachments: attachments,
+            addresses,
+            headers,
+            attachments: mime.attachments,
+            inline_parts: mime.inline_parts,
             body: body,
+            text: mime.text,
+            html: mime.html,
+            parts: mime.root,
+            envelope,
         }
     }
 
     /// ヘッダー情報を抽出する補助関数
-    fn extract_headers(
-        parsed: &ParsedMail,
-    ) -> (
-        Option<String>,
-        Option<String>,
-        Option<String>,
-        Vec<AttachmentData>,
-        String,
-    ) {
+    fn extract_headers(parsed: &ParsedMail) -> (Option<String>, Option<String>, Option<String>) {
         let mut subject = None;
         let mut from = None;
         let mut to = None;
@@ -105,84 +199,203 @@ impl EmailData {
             }
         }
 
-        (
-            subject,
-            from,
-            to,
-            AttachmentData::extract_attachement(parsed),
-            Self::extract_body(parsed),
-        )
+        (subject, from, to)
     }
 
-    fn extract_body(parsed: &ParsedMail) -> String {
-        // multipart でなければ全体が本文とみなす
-        if parsed.subparts.is_empty() {
-            match parsed.get_body() {
-                Ok(body) => {
-                    return body;
-                }
-                Err(e) => {
-                    error!("{:?}", e);
-                    return "".to_string();
-                }
-            }
-        } else {
-            for subpart in &parsed.subparts {
-                let mimetype = subpart.ctype.mimetype.to_lowercase();
-                if (mimetype == TEXT_PLAIN || mimetype == TEXT_HTML)
-                    && subpart.get_content_disposition().disposition == DispositionType::Inline
-                {
-                    if let Ok(body) = subpart.get_body() {
-                        return body;
-                    }
-                }
+    /// 指定したSMTP AUTHのユーザーが送信したメールかどうか
+    pub fn is_sent_by(&self, username: &str) -> bool {
+        self.envelope.get_auth_user().as_deref() == Some(username)
+    }
+
+    /// ## Summary
+    /// 受信箱として扱うアドレスの一覧を取得する
+    ///
+    /// ## Note
+    /// エンベロープの受信者(RCPT TO)のアドレスを小文字にして重複を除いたもの
+    /// Toヘッダーは送信者が自由に書けるので、エンベロープに受信者がない場合(mboxの取り込み等)だけ使う
+    /// (Bcc宛てのメールがToヘッダーのアドレスの受信箱に見えないようにする)
+    pub fn get_inbox_addresses(&self) -> Vec<String> {
+        let mut addresses = self.envelope.get_recipients();
+        if addresses.is_empty() {
+            addresses = self
+                .addresses
+                .get_to()
+                .iter()
+                .map(|mailbox| mailbox.get_address().clone())
+                .collect();
+        }
+
+        let mut inboxes: Vec<String> = vec![];
+        for address in addresses {
+            let address = address.trim().to_lowercase();
+            if !address.is_empty() && !inboxes.contains(&address) {
+                inboxes.push(address);
             }
         }
-        "".into()
+        inboxes
+    }
+
+    /// 指定したアドレス宛てに届いたメールかどうか(大文字小文字は区別しない)
+    pub fn is_delivered_to(&self, address: &str) -> bool {
+        let address = address.trim().to_lowercase();
+        self.get_inbox_addresses().contains(&address)
+    }
+
+    /// ファイル名が一致する添付ファイルを探す(同名のファイルが複数あれば最初のもの)
+    pub fn find_attachment(&self, filename: &str) -> Option<&AttachmentData> {
+        self.attachments
+            .iter()
+            .find(|attachment| attachment.filename.as_deref() == Some(filename))
     }
 
-    pub fn convert_to_email_summary(&self, i: usize) -> EmailSummary {
+    /// ## Summary
+    /// Content-IDで参照されるパートを探す(インラインパート、なければ添付ファイル)
+    ///
+    /// ## Parameters
+    /// - `content_id`: Content-ID(`<` `>` はあってもなくてもよい)
+    pub fn find_by_content_id(&self, content_id: &str) -> Option<&AttachmentData> {
+        let content_id = mime::normalize_content_id(content_id);
+        let matches = |part: &&AttachmentData| {
+            part.content_id
+                .as_ref()
+                .is_some_and(|id| id.eq_ignore_ascii_case(&content_id))
+        };
+        self.inline_parts
+            .iter()
+            .find(matches)
+            .or_else(|| self.attachments.iter().find(matches))
+    }
+
+    /// HTMLの本文(`cid:` の参照はインラインパートのAPIのURLに書き換える)
+    pub fn get_resolved_html(&self) -> Option<String> {
+        self.html
+            .as_ref()
+            .map(|html| mime::resolve_cid_references(html, &self.id))
+    }
+
+    pub fn convert_to_email_summary(&self) -> EmailSummary {
         EmailSummary {
-            id: i,
+            id: self.id.clone(),
             received_time: self.received_time.format("%Y-%m-%d %H:%M").to_string(),
             subject: self.subject.clone(),
             from: self.from.clone(),
             to: self.to.clone(),
-            raw: self.raw.clone(),
+            addresses: self.addresses.clone(),
+            mail_from: self.envelope.get_sender(),
+            rcpt_to: self.envelope.get_recipients(),
+            extensions: self.envelope.get_extensions().clone(),
+            auth_user: self.envelope.get_auth_user().clone(),
+            client_cert_subject: self.envelope.get_client_cert_subject().clone(),
+            raw: String::from_utf8_lossy(&self.raw).to_string(),
             attachments: self
                 .attachments
                 .iter()
                 .filter_map(|attachment| attachment.filename.clone())
                 .collect(),
             body: self.body.clone(),
+            text: self.text.clone(),
+            // cid:を解決したものを返す
+            html: self.get_resolved_html(),
         }
     }
 }
 
-impl AttachmentData {
-    pub fn extract_attachement(parsed: &ParsedMail) -> Vec<AttachmentData> {
-        let mut attachments = vec![];
-
-        for subpart in &parsed.subparts {
-            let content_dispositon = subpart.get_content_disposition();
-            debug!("{:?}", &content_dispositon);
-            match content_dispositon.disposition {
-                mailparse::DispositionType::Inline => {}
-                mailparse::DispositionType::Attachment => {
-                    let filename = content_dispositon.params.get("filename").cloned();
-
-                    if let Ok(body) = subpart.get_body_raw() {
-                        attachments.push(Self {
-                            filename: filename,
-                            content_type: subpart.ctype.mimetype.clone(),
-                            data: Arc::new(body),
-                        });
-                    }
-                }
-                _ => {}
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::SmtpPath;
+
+    #[test]
+    fn assigns_a_unique_id_to_each_message() {
+        let content = b"Subject: test\r\n\r\nbody\r\n".to_vec();
+        let first = EmailData::new(content.clone(), Envelope::default(), Local::now());
+        let second = EmailData::new(content, Envelope::default(), Local::now());
+        assert_ne!(first.get_id(), second.get_id());
+        assert!(Uuid::parse_str(first.get_id()).is_ok());
+        // 一覧・詳細のAPIでも同じIDを返す
+        assert_eq!(&first.convert_to_email_summary().id, first.get_id());
+    }
+
+    #[test]
+    fn is_sent_by_matches_auth_user() {
+        let mut envelope = Envelope::with_sender("a@example.com".to_string());
+        envelope.set_auth_user(Some("alice".to_string()));
+        let email = EmailData::new(b"Subject: test\r\n\r\n".to_vec(), envelope, Local::now());
+        assert!(email.is_sent_by("alice"));
+        assert!(!email.is_sent_by("bob"));
+        assert_eq!(
+            email.convert_to_email_summary().auth_user.as_deref(),
+            Some("alice")
+        );
+
+        // 認証せずに送信されたメールは誰のものでもない
+        let anonymous = EmailData::new(vec![], Envelope::default(), Local::now());
+        assert!(!anonymous.is_sent_by(""));
+    }
+
+    #[test]
+    fn inbox_addresses_prefer_envelope_recipients() {
+        let to_header = b"To: bob@example.com, Team: carol@example.com;\r\n\r\n";
+        let mut envelope = Envelope::default();
+        envelope.begin(SmtpPath::parse("MAIL FROM:<a@example.com>", "MAIL FROM:").unwrap());
+        envelope.add_rcpt_to(SmtpPath::parse("RCPT TO:<Dave@Example.com>", "RCPT TO:").unwrap());
+        let email = EmailData::new(to_header.to_vec(), envelope, Local::now());
+        // RCPT TOがあればToヘッダーのアドレスは使わない
+        assert_eq!(email.get_inbox_addresses(), ["dave@example.com"]);
+        assert!(email.is_delivered_to(" DAVE@example.com"));
+        assert!(!email.is_delivered_to("bob@example.com"));
+        assert!(!email.is_delivered_to("a@example.com"));
+
+        // エンベロープがなければToヘッダーで振り分ける
+        let imported = EmailData::new(to_header.to_vec(), Envelope::default(), Local::now());
+        assert_eq!(
+            imported.get_inbox_addresses(),
+            ["bob@example.com", "carol@example.com"]
+        );
+    }
+
+    fn email(content: &str) -> EmailData {
+        EmailData::new(
+            content.as_bytes().to_vec(),
+            Envelope::default(),
+            Local::now(),
+        )
+    }
+
+    #[test]
+    fn body_prefers_text_part() {
+        let email = email(
+            "Subject: test\r\nContent-Type: multipart/alternative; boundary=B\r\n\r\n\
+             --B\r\nContent-Type: text/html\r\n\r\n<p class=\"greeting\">Hello html</p>\r\n\
+             --B\r\nContent-Type: text/plain\r\n\r\nHello text\r\n--B--\r\n",
+        );
+        assert_eq!(email.get_body().trim_end(), "Hello text");
+        assert_eq!(
+            email.get_html().as_deref().map(str::trim_end),
+            Some("<p class=\"greeting\">Hello html</p>")
+        );
+    }
+
+    #[test]
+    fn search_does_not_match_html_markup() {
+        let email = email(
+            "Subject: test\r\nContent-Type: text/html\r\n\r\n<p class=\"greeting\">Hello</p>\r\n",
+        );
+        assert_eq!(email.get_body(), "Hello");
+        assert!(SearchQuery::new(Some("hello".to_string())).matches(&email));
+        assert!(!SearchQuery::new(Some("greeting".to_string())).matches(&email));
+    }
 
-        attachments
+    #[test]
+    fn finds_attachment_by_filename() {
+        let email = email(
+            "Subject: test\r\nContent-Type: multipart/mixed; boundary=B\r\n\r\n\
+             --B\r\nContent-Type: text/plain\r\n\r\nbody\r\n\
+             --B\r\nContent-Type: text/plain\r\n\
+             Content-Disposition: attachment; filename=\"a.txt\"\r\n\r\nattached\r\n--B--\r\n",
+        );
+        let attachment = email.find_attachment("a.txt").unwrap();
+        assert_eq!(attachment.get_data().as_slice(), b"attached\r\n");
+        assert!(email.find_attachment("b.txt").is_none());
     }
 }
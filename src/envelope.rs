@@ -0,0 +1,235 @@
+use rumbok::Getter;
+use serde::{Deserialize, Serialize};
+
+/// MAIL FROM / RCPT TO で受け取ったパス(アドレス + ESMTPパラメータ)
+#[derive(Clone, Debug, Default, Getter, Serialize, Deserialize)]
+pub struct SmtpPath {
+    /// `<>`(null sender)の場合は空文字
+    address: String,
+    /// `SIZE=1000` `BODY=8BITMIME` 等のESMTPパラメータ(キーは大文字)
+    params: Vec<(String, Option<String>)>,
+}
+
+/// 1トランザクション分のSMTPエンベロープ
+#[derive(Clone, Debug, Default, Getter, Serialize, Deserialize)]
+pub struct Envelope {
+    mail_from: Option<SmtpPath>,
+    rcpt_to: Vec<SmtpPath>,
+    /// このトランザクションで使われたESMTP拡張(8BITMIME, SMTPUTF8, PIPELINING 等)
+    #[serde(default)]
+    extensions: Vec<String>,
+    /// 送信したSMTP AUTHのユーザー(認証せずに送信された場合はNone)
+    #[serde(default)]
+    auth_user: Option<String>,
+    /// 検証済みのクライアント証明書のサブジェクト(mTLSで接続された場合)
+    #[serde(default)]
+    client_cert_subject: Option<String>,
+}
+
+impl SmtpPath {
+    /// ## Summary
+    /// `MAIL FROM:<addr> PARAM=VALUE ...` 形式の文字列からパスを取り出す
+    ///
+    /// ## Parameters
+    /// - `input`: クライアントから受け取った行(大文字変換前)
+    /// - `prefix`: `MAIL FROM:` / `RCPT TO:`
+    ///
+    /// ## Returns
+    /// 構文が不正な場合はNone
+    pub fn parse(input: &str, prefix: &str) -> Option<Self> {
+        let line = input.trim();
+        if !line
+            .get(..prefix.len())
+            .is_some_and(|head| head.eq_ignore_ascii_case(prefix))
+        {
+            return None;
+        }
+        let rest = line[prefix.len()..].trim_start();
+
+        // <addr> 形式が基本だが、括弧なしで送ってくるクライアントも許容する
+        let (address, rest) = if let Some(stripped) = rest.strip_prefix('<') {
+            let end = stripped.find('>')?;
+            (&stripped[..end], &stripped[end + 1..])
+        } else {
+            match rest.find(' ') {
+                Some(end) => (&rest[..end], &rest[end..]),
+                None => (rest, ""),
+            }
+        };
+
+        // source route(@a,@b:user@host)は無視してアドレス部分のみ残す
+        let address = match address.rfind(':') {
+            Some(i) if address.starts_with('@') => &address[i + 1..],
+            _ => address,
+        };
+        if address.chars().any(|c| c.is_whitespace()) {
+            return None;
+        }
+
+        let params = rest
+            .split_whitespace()
+            .map(|param| match param.split_once('=') {
+                Some((key, value)) => (key.to_uppercase(), Some(value.to_string())),
+                None => (param.to_uppercase(), None),
+            })
+            .collect();
+
+        Some(Self {
+            address: address.to_string(),
+            params,
+        })
+    }
+
+    /// 対応していないESMTPパラメータがあればその名前を返す(`supported` は大文字)
+    pub fn find_unsupported_param(&self, supported: &[&str]) -> Option<&str> {
+        self.params
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .find(|key| !supported.contains(key))
+    }
+
+    /// 指定したESMTPパラメータの値を取得(キーは大文字小文字を区別しない)
+    pub fn get_param(&self, key: &str) -> Option<&Option<String>> {
+        self.params
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v)
+    }
+}
+
+impl Envelope {
+    /// 送信者のみ分かっている場合のエンベロープ(mboxの取り込み等)
+    pub fn with_sender(address: String) -> Self {
+        Self {
+            mail_from: Some(SmtpPath {
+                address,
+                params: vec![],
+            }),
+            rcpt_to: vec![],
+            extensions: vec![],
+            auth_user: None,
+            client_cert_subject: None,
+        }
+    }
+
+    /// MAIL FROMを受け取ったら新しいトランザクションを開始する
+    pub fn begin(&mut self, mail_from: SmtpPath) {
+        self.mail_from = Some(mail_from);
+        self.rcpt_to.clear();
+        self.extensions.clear();
+        self.auth_user = None;
+        self.client_cert_subject = None;
+    }
+
+    /// 送信したSMTP AUTHのユーザーを記録する
+    pub fn set_auth_user(&mut self, auth_user: Option<String>) {
+        self.auth_user = auth_user;
+    }
+
+    /// 送信したクライアント証明書のサブジェクトを記録する
+    pub fn set_client_cert_subject(&mut self, client_cert_subject: Option<String>) {
+        self.client_cert_subject = client_cert_subject;
+    }
+
+    /// 使われたESMTP拡張を記録する(重複は無視)
+    pub fn add_extension(&mut self, extension: &str) {
+        if !self.extensions.iter().any(|e| e == extension) {
+            self.extensions.push(extension.to_string());
+        }
+    }
+
+    pub fn add_rcpt_to(&mut self, rcpt_to: SmtpPath) {
+        self.rcpt_to.push(rcpt_to);
+    }
+
+    /// エンベロープ送信者(null senderは空文字)
+    pub fn get_sender(&self) -> Option<String> {
+        self.mail_from.as_ref().map(|path| path.address.clone())
+    }
+
+    /// エンベロープ受信者の一覧
+    pub fn get_recipients(&self) -> Vec<String> {
+        self.rcpt_to
+            .iter()
+            .map(|path| path.address.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_address_and_params() {
+        let path = SmtpPath::parse(
+            "mail from:<a@example.com> size=100 BODY=8BITMIME SMTPUTF8",
+            "MAIL FROM:",
+        )
+        .unwrap();
+        assert_eq!(path.get_address(), "a@example.com");
+        assert_eq!(
+            path.get_params(),
+            &[
+                ("SIZE".to_string(), Some("100".to_string())),
+                ("BODY".to_string(), Some("8BITMIME".to_string())),
+                ("SMTPUTF8".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_null_sender_and_source_route() {
+        let null = SmtpPath::parse("MAIL FROM:<>", "MAIL FROM:").unwrap();
+        assert_eq!(null.get_address(), "");
+        let routed =
+            SmtpPath::parse("RCPT TO:<@a.example,@b.example:c@example.com>", "RCPT TO:").unwrap();
+        assert_eq!(routed.get_address(), "c@example.com");
+        // 括弧なしのアドレスも受け付ける
+        let bare = SmtpPath::parse("RCPT TO: c@example.com", "RCPT TO:").unwrap();
+        assert_eq!(bare.get_address(), "c@example.com");
+    }
+
+    #[test]
+    fn rejects_malformed_paths() {
+        assert!(SmtpPath::parse("RCPT TO:<a@example.com>", "MAIL FROM:").is_none());
+        assert!(SmtpPath::parse("MAIL FROM:<a@example.com", "MAIL FROM:").is_none());
+        assert!(SmtpPath::parse("MAIL FROM:<a @example.com>", "MAIL FROM:").is_none());
+        assert!(SmtpPath::parse("MAIL", "MAIL FROM:").is_none());
+    }
+
+    #[test]
+    fn begin_starts_a_new_transaction() {
+        let mut envelope = Envelope::default();
+        envelope.begin(SmtpPath::parse("MAIL FROM:<a@example.com>", "MAIL FROM:").unwrap());
+        envelope.add_rcpt_to(SmtpPath::parse("RCPT TO:<b@example.com>", "RCPT TO:").unwrap());
+        assert_eq!(envelope.get_sender().as_deref(), Some("a@example.com"));
+        assert_eq!(envelope.get_recipients(), ["b@example.com"]);
+
+        envelope.begin(SmtpPath::parse("MAIL FROM:<>", "MAIL FROM:").unwrap());
+        assert_eq!(envelope.get_sender().as_deref(), Some(""));
+        assert!(envelope.get_recipients().is_empty());
+    }
+
+    #[test]
+    fn records_each_extension_once() {
+        let mut envelope = Envelope::default();
+        envelope.begin(SmtpPath::parse("MAIL FROM:<a@example.com>", "MAIL FROM:").unwrap());
+        envelope.add_extension("8BITMIME");
+        envelope.add_extension("PIPELINING");
+        envelope.add_extension("8BITMIME");
+        assert_eq!(envelope.get_extensions(), &["8BITMIME", "PIPELINING"]);
+
+        // 次のトランザクションには引き継がない
+        envelope.begin(SmtpPath::parse("MAIL FROM:<a@example.com>", "MAIL FROM:").unwrap());
+        assert!(envelope.get_extensions().is_empty());
+    }
+
+    #[test]
+    fn finds_unsupported_params() {
+        let path =
+            SmtpPath::parse("MAIL FROM:<a@example.com> SIZE=1 RET=HDRS", "MAIL FROM:").unwrap();
+        assert_eq!(path.find_unsupported_param(&["SIZE", "BODY"]), Some("RET"));
+        assert_eq!(path.find_unsupported_param(&["SIZE", "RET"]), None);
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use rumbok::Getter;
+use serde::Deserialize;
+
+/// `GET /api/emails/{id}/html` のクエリパラメータ
+#[derive(Deserialize, Getter)]
+pub struct HtmlPreviewQuery {
+    /// trueならリモートの画像(http / https)を読み込む
+    #[serde(default)]
+    remote_images: bool,
+}
+
+/// ## Summary
+/// サニタイズしたHTMLのプレビュー
+#[derive(Getter)]
+pub struct HtmlPreview {
+    /// iframeにそのまま表示できるHTML文書
+    document: String,
+    /// ブロックしたリモート画像の数
+    blocked_remote_images: usize,
+}
+
+impl HtmlPreview {
+    /// ## Summary
+    /// HTMLの本文をサニタイズしてプレビューを作る
+    ///
+    /// ## Parameters
+    /// - `html`: HTMLの本文(`cid:` の参照は書き換え済みのもの)
+    /// - `allow_remote_images`: リモートの画像を残すか
+    ///
+    /// ## Note
+    /// script・iframe・form・イベントハンドラ・`javascript:` や `cid:` のURL・相対URLのリンク等は取り除く
+    /// HTMLメールは見た目をstyleに頼るので、style要素・style属性と表の装飾用の属性は残す
+    /// (styleの `url()` で読み込まれるリソースはContent-Security-Policyで止める)
+    pub fn from_html(html: &str, allow_remote_images: bool) -> Self {
+        let blocked = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&blocked);
+
+        let document = ammonia::Builder::default()
+            .add_tags(&["style", "font"])
+            .rm_clean_content_tags(&["style"])
+            .add_generic_attributes(&[
+                "style",
+                "class",
+                "id",
+                "dir",
+                "align",
+                "valign",
+                "bgcolor",
+                "background",
+                "width",
+                "height",
+                "border",
+            ])
+            .add_tag_attributes("table", &["cellpadding", "cellspacing"])
+            .add_tag_attributes("td", &["colspan", "rowspan", "nowrap"])
+            .add_tag_attributes("th", &["colspan", "rowspan", "nowrap"])
+            .add_tag_attributes("font", &["color", "face", "size"])
+            .add_url_schemes(&["data"])
+            .attribute_filter(move |element, attribute, value| {
+                let is_image =
+                    (element == "img" && attribute == "src") || attribute == "background";
+                if is_data_url(value) && !is_image {
+                    // data: はインラインの画像にだけ許可する
+                    return None;
+                }
+                if element == "a" && attribute == "href" && !has_scheme(value) {
+                    // 相対URLのリンクはWeb UIと同じオリジン(パートのAPI等)を開けてしまうので残さない
+                    return None;
+                }
+                if is_image && is_remote_url(value) && !allow_remote_images {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                Some(Cow::Borrowed(value))
+            })
+            .clean(html)
+            .to_string();
+
+        Self {
+            document: wrap_document(&document),
+            blocked_remote_images: blocked.load(Ordering::Relaxed),
+        }
+    }
+
+    /// テキストの本文を `<pre>` で囲んだプレビューを作る
+    pub fn from_text(text: &str) -> Self {
+        let body = format!(
+            "<pre style=\"white-space: pre-wrap; font-family: inherit;\">{}</pre>",
+            htmlescape::encode_minimal(text)
+        );
+        Self {
+            document: wrap_document(&body),
+            blocked_remote_images: 0,
+        }
+    }
+}
+
+/// ## Summary
+/// プレビューに付けるContent-Security-Policy
+///
+/// ## Note
+/// スクリプト・フォーム・外部のスタイルシート等はすべて禁止し、画像は同じオリジン(インラインパート)と `data:` のみ許可する
+/// `sandbox` でスクリプトの実行を止めたうえで、リンクだけは新しいタブで開けるようにする
+/// (新しいタブもサンドボックスのまま開くよう `allow-popups-to-escape-sandbox` は付けない)
+pub fn content_security_policy(allow_remote_images: bool) -> String {
+    let remote = if allow_remote_images {
+        " http: https:"
+    } else {
+        ""
+    };
+    format!(
+        "default-src 'none'; img-src 'self' data:{}; style-src 'unsafe-inline'; \
+         base-uri 'none'; form-action 'none'; frame-ancestors 'self'; \
+         sandbox allow-popups",
+        remote
+    )
+}
+
+/// 本文をHTML文書として包む(リンクは新しいタブで開く)
+fn wrap_document(body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><base target=\"_blank\"></head><body>{}</body></html>",
+        body
+    )
+}
+
+fn is_remote_url(value: &str) -> bool {
+    let value = value.trim_start().to_ascii_lowercase();
+    value.starts_with("http:") || value.starts_with("https:") || value.starts_with("//")
+}
+
+fn is_data_url(value: &str) -> bool {
+    value.trim_start().to_ascii_lowercase().starts_with("data:")
+}
+
+/// `https:` `mailto:` 等のスキームで始まる絶対URLか
+fn has_scheme(value: &str) -> bool {
+    value
+        .trim_start()
+        .split_once(':')
+        .is_some_and(|(scheme, _)| {
+            !scheme.is_empty()
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime;
+
+    #[test]
+    fn removes_scripts_and_event_handlers() {
+        let preview = HtmlPreview::from_html(
+            r#"<script>alert(1)</script><p onclick="x()" style="color:red">hi</p><a href="javascript:alert(1)">x</a>"#,
+            false,
+        );
+        let document = preview.get_document();
+        assert!(!document.contains("<script"));
+        assert!(!document.contains("onclick"));
+        assert!(!document.contains("javascript:"));
+        assert!(document.contains(r#"<p style="color:red">hi</p>"#));
+    }
+
+    #[test]
+    fn blocks_remote_images_unless_allowed() {
+        let html = r#"<img src="https://tracker.example.com/p.gif"><img src="data:image/png;base64,AAAA">"#;
+        let blocked = HtmlPreview::from_html(html, false);
+        assert_eq!(*blocked.get_blocked_remote_images(), 1);
+        assert!(!blocked.get_document().contains("tracker.example.com"));
+        assert!(blocked.get_document().contains("data:image/png"));
+
+        let allowed = HtmlPreview::from_html(html, true);
+        assert_eq!(*allowed.get_blocked_remote_images(), 0);
+        assert!(allowed.get_document().contains("tracker.example.com"));
+    }
+
+    #[test]
+    fn does_not_link_to_same_origin_parts() {
+        // cid:のリンクやパートのAPIへの相対リンクを開くと、サンドボックスの外でパートが表示される
+        let html = mime::resolve_cid_references(
+            r#"<a href="cid:evil@x">a</a><a href="cid/evil%40x">b</a><a href="/api/emails/id-1/parts/1.2">c</a><img src="cid:logo@x"><a href="https://example.com/">d</a>"#,
+            "id-1",
+        );
+        let preview = HtmlPreview::from_html(&html, false);
+        let document = preview.get_document();
+        assert!(!document.contains("evil"));
+        assert!(!document.contains("/parts/"));
+        assert!(document.contains(r#"<img src="/api/emails/id-1/cid/logo%40x">"#));
+        assert!(document.contains(r#"href="https://example.com/""#));
+    }
+
+    #[test]
+    fn popups_stay_sandboxed() {
+        let policy = content_security_policy(false);
+        assert!(policy.contains("sandbox allow-popups"));
+        assert!(!policy.contains("allow-popups-to-escape-sandbox"));
+        assert!(!policy.contains("http:"));
+    }
+}
@@ -0,0 +1,178 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use warp::{
+    http::{header::WWW_AUTHENTICATE, HeaderValue, StatusCode},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    auth::user_db::{self, UserDatabase},
+    storage::mailbox::MailboxStorage,
+    util::base64,
+    EmailStore,
+};
+
+/// 401で返すBasic認証のrealm
+const REALM: &str = "Basic realm=\"rust-mail-server\", charset=\"UTF-8\"";
+
+/// 認証が必要なAPIを資格情報なし・誤った資格情報で呼んだ場合のRejection(401として扱われる)
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// 管理用APIを許可されていない呼び出し元から呼んだ場合のRejection(403として扱われる)
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
+/// ## Summary
+/// リクエストしたユーザーのメールボックスを返すFilter
+///
+/// ## Parameters
+/// - `email_store`: すべてのメールを保存しているストア
+/// - `users`: SMTP AUTHと同じユーザーデータベース
+///
+/// ## Note
+/// ユーザーデータベースがない場合は(SMTP AUTHと同じく)認証せず、すべてのメールを返す
+/// ある場合はBasic認証を必須にし、そのユーザーが送信したメールだけを見せる
+pub fn with_mailbox(
+    email_store: EmailStore,
+    users: Option<Arc<UserDatabase>>,
+) -> impl Filter<Extract = (EmailStore,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(
+        move |authorization: Option<String>| {
+            let email_store = email_store.clone();
+            let users = users.clone();
+            async move {
+                let Some(users) = users else {
+                    return Ok(email_store);
+                };
+                match authorization.as_deref().and_then(basic_credentials) {
+                    Some((username, password)) if users.verify(&username, &password) => Ok(
+                        EmailStore(Arc::new(MailboxStorage::new(email_store.0, username))),
+                    ),
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        },
+    )
+}
+
+/// ## Summary
+/// 管理用APIの呼び出し元を確認するFilter(許可されていなければ403)
+///
+/// ## Parameters
+/// - `admin_token`: 設定した管理用トークン
+///
+/// ## Note
+/// トークンを設定した場合は `Authorization: Bearer <トークン>` が必要
+/// 設定していない場合はループバックアドレスからのリクエストのみ受け付ける
+pub fn with_admin(
+    admin_token: Option<String>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |remote: Option<SocketAddr>, authorization: Option<String>| {
+                let allowed = is_admin(remote, authorization.as_deref(), admin_token.as_deref());
+                async move {
+                    if allowed {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::custom(Forbidden))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// 管理用APIの呼び出しを許可するか
+fn is_admin(
+    remote: Option<SocketAddr>,
+    authorization: Option<&str>,
+    admin_token: Option<&str>,
+) -> bool {
+    match admin_token {
+        Some(admin_token) => authorization
+            .and_then(|authorization| authorization.trim().strip_prefix("Bearer "))
+            .is_some_and(|token| {
+                user_db::constant_time_eq(token.trim().as_bytes(), admin_token.as_bytes())
+            }),
+        // IPv4射影アドレス(::ffff:127.0.0.1)もループバックとみなす
+        None => remote.is_some_and(|remote| remote.ip().to_canonical().is_loopback()),
+    }
+}
+
+/// `Authorization: Basic ...` の値からユーザー名とパスワードを取り出す
+fn basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let credentials = base64::decode(credentials).ok()?;
+    let (username, password) = credentials.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// 認証の失敗を `WWW-Authenticate` 付きの401、管理用APIの拒否を403に変換する(それ以外のRejectionはそのまま返す)
+pub async fn handle_rejection(rejection: Rejection) -> Result<warp::reply::Response, Rejection> {
+    if rejection.find::<Forbidden>().is_some() {
+        return Ok(warp::reply::with_status("Forbidden", StatusCode::FORBIDDEN).into_response());
+    }
+    if rejection.find::<Unauthorized>().is_none() {
+        return Err(rejection);
+    }
+    let mut response =
+        warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED).into_response();
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static(REALM));
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_basic_credentials() {
+        // alice:p:ss (パスワードには `:` を含められる)
+        assert_eq!(
+            basic_credentials("Basic YWxpY2U6cDpzcw=="),
+            Some(("alice".to_string(), "p:ss".to_string()))
+        );
+        assert_eq!(
+            basic_credentials("basic YWxpY2U6cDpzcw=="),
+            Some(("alice".to_string(), "p:ss".to_string()))
+        );
+        assert_eq!(basic_credentials("Bearer YWxpY2U6cDpzcw=="), None);
+        assert_eq!(basic_credentials("Basic not-base64"), None);
+        // `:` のない値は不正
+        assert_eq!(basic_credentials("Basic YWxpY2U="), None);
+    }
+
+    #[test]
+    fn admin_requires_loopback_without_token() {
+        let loopback = "127.0.0.1:50000".parse().ok();
+        let mapped = "[::ffff:127.0.0.1]:50000".parse().ok();
+        let remote = "192.0.2.1:50000".parse().ok();
+        assert!(is_admin(loopback, None, None));
+        assert!(is_admin(mapped, None, None));
+        assert!(!is_admin(remote, None, None));
+        assert!(!is_admin(None, None, None));
+    }
+
+    #[test]
+    fn admin_requires_token_when_configured() {
+        let loopback = "127.0.0.1:50000".parse().ok();
+        let remote = "192.0.2.1:50000".parse().ok();
+        assert!(is_admin(remote, Some("Bearer secret"), Some("secret")));
+        // トークンを設定した場合はループバックからでもトークンが必要
+        assert!(!is_admin(loopback, None, Some("secret")));
+        assert!(!is_admin(remote, Some("Bearer wrong"), Some("secret")));
+        assert!(!is_admin(remote, Some("Basic secret"), Some("secret")));
+    }
+}
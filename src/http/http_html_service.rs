@@ -1,3 +1,4 @@
+use log::error;
 use rumbok::Singleton;
 
 use crate::EmailStore;
@@ -173,17 +174,17 @@ impl HtmlData {
     }
 
     pub async fn init_html(&self, emial_store: EmailStore,content:String,) -> String {
-        let store = emial_store.0.lock().await;
+        let store = emial_store.0.list().await.unwrap_or_default();
         let mut mail_list_element = "".to_string();
         let mut mail_header_element = "".to_string();
         let mut mail_file_item_element = "".to_string();
         let mut mail_body_element = "".to_string();
-        for (i, email) in store.iter().enumerate() {
+        for email in store.iter() {
             mail_list_element.push_str(r#"<div class="mail-item">"#);
             mail_list_element.push_str(&format!(
-                r#"<div class="mail-summary" id={} onclick="mailItemClick(this)">{}</div>"#,
-                i,
-                email.get_subject().clone().unwrap_or_default()
+                r#"<div class="mail-summary" id="{}" onclick="mailItemClick(this)">{}</div>"#,
+                htmlescape::encode_attribute(email.get_id()),
+                htmlescape::encode_minimal(&email.get_subject().clone().unwrap_or_default())
             ));
             mail_list_element
                 .push_str(&format!(r#"<div class="mail-date">2025-02-06 12:34</div>"#));
@@ -226,16 +227,26 @@ impl HtmlData {
 }
 
 pub async fn init_html(emial_store: EmailStore,content:String,) -> String {
-  let store = emial_store.0.lock().await;
+  let store = match emial_store.0.list().await {
+      Ok(store) => store,
+      Err(e) => {
+          error!("{}", e);
+          vec![]
+      }
+  };
   let mut mail_list_element = "".to_string();
   let mut mail_header_element = "".to_string();
   let mut mail_file_item_element = "".to_string();
   let mut mail_body_element = "".to_string();
-  for (i, email) in store.iter().enumerate() {
-      mail_list_element.push_str(&format!(r#"<div class="mail-item" id={} onclick="mailItemClick(this)">"#,i));
+  for email in store.iter() {
+      // 件名はメールの送信者が自由に書けるので、必ずエスケープする
+      mail_list_element.push_str(&format!(
+          r#"<div class="mail-item" id="{}" onclick="mailItemClick(this)">"#,
+          htmlescape::encode_attribute(email.get_id())
+      ));
       mail_list_element.push_str(&format!(
           r#"<div class="mail-summary">{}</div>"#,
-          email.get_subject().clone().unwrap_or_default()
+          htmlescape::encode_minimal(&email.get_subject().clone().unwrap_or_default())
       ));
       mail_list_element
           .push_str(&format!(r#"<div class="mail-date">2025-02-06 12:34</div>"#));
@@ -270,4 +281,28 @@ pub async fn init_html(emial_store: EmailStore,content:String,) -> String {
   //     .replace("$mail_file", &mail_file_item_element)
   //     .replace("$mail_body", &mail_body_element)
   content.replace("$mail_list", &mail_list_element)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::Local;
+
+    use super::*;
+    use crate::{email::EmailData, envelope::Envelope, storage::memory::MemoryStorage};
+
+    #[tokio::test]
+    async fn escapes_subject_in_mail_list() {
+        let store = EmailStore(Arc::new(MemoryStorage::default()));
+        let content = b"Subject: <img src=x onerror=alert(1)>\r\n\r\nbody\r\n".to_vec();
+        let email = EmailData::new(content, Envelope::default(), Local::now());
+        let id = htmlescape::encode_attribute(email.get_id());
+        store.0.save(email).await.unwrap();
+
+        let html = init_html(store, "$mail_list".to_string()).await;
+        assert!(!html.contains("<img"), "{}", html);
+        let subject = "&lt;img src=x onerror=alert(1)&gt;";
+        assert!(html.contains(subject), "{}", html);
+        assert!(html.contains(&format!(r#"id="{}""#, id)), "{}", html);
+    }
+}
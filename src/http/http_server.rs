@@ -1,27 +1,59 @@
-use std::io::Cursor;
+use std::{path::PathBuf, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use futures::{SinkExt, StreamExt};
 use log::{error, info};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use tokio::sync::broadcast;
 use warp::Filter;
 
 use crate::{
-    email::{AttachmentData, EmailSummary, SearchQuery},
-    EmailStore,
+    auth::user_db::UserDatabase,
+    command::WebSocketCommand,
+    config::{tls::ReloadableTls, Config},
+    email::{EmailData, EmailSummary, SearchQuery},
+    mail_io, mime, EmailStore,
 };
 
-use super::http_html_service::{self, HtmlData};
+use super::{
+    html_preview::{self, HtmlPreview, HtmlPreviewQuery},
+    http_auth, http_html_service,
+};
+
+/// インポートで受け付けるリクエストボディの上限(100MB)
+const IMPORT_BODY_LIMIT: u64 = 100 * 1024 * 1024;
+
+/// `filename*=` でエンコードしない文字(RFC 5987のattr-char)
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
 
 /// HTTP サーバーを起動して、受信メールを Web 画面で表示する関数
 pub async fn run_http_server(
+    config: Arc<Config>,
     email_store: EmailStore,
     ws_tx: broadcast::Sender<String>,
+    tls: Arc<ReloadableTls>,
+    users: Option<Arc<UserDatabase>>,
 ) -> Result<()> {
+    let http_config = config.get_http();
     // email_store を各リクエストで利用できるようにする
-    let store_filter = warp::any().map(move || email_store.clone());
+    // (ユーザーデータベースがある場合はBasic認証したユーザーのメールボックスのみ)
+    let store_filter = http_auth::with_mailbox(email_store, users);
     // ルートパスにアクセスしたときのハンドラ
+    let index_html = http_config.get_index_html().clone();
     let index = warp::path::end()
+        .and(warp::any().map(move || index_html.clone()))
         .and(store_filter.clone())
         .and_then(handle_index);
 
@@ -32,13 +64,70 @@ pub async fn run_http_server(
         .and(store_filter.clone())
         .and_then(handle_api_emails_get);
 
-    let api_email_detail = warp::path!("api" / "emails" / usize)
+    // API: GET /api/users → メールを送信したSMTP AUTHのユーザー一覧
+    let api_users = warp::path!("api" / "users")
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and_then(handle_api_users);
+
+    // API: GET /api/users/{name}/emails → 指定ユーザーが送信したメールのみ返す(?q= で絞り込み)
+    let api_user_emails = warp::path!("api" / "users" / String / "emails")
+        .and(warp::get())
+        .and(warp::query::<SearchQuery>())
+        .and(store_filter.clone())
+        .and_then(handle_api_user_emails);
+
+    // API: GET /api/inboxes → これまでに受信したことのある宛先アドレス一覧
+    let api_inboxes = warp::path!("api" / "inboxes")
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and_then(handle_api_inboxes);
+
+    // API: GET /api/inboxes/{address}/emails → 指定アドレス宛てのメールのみ返す(?q= で絞り込み)
+    let api_inbox_emails = warp::path!("api" / "inboxes" / String / "emails")
+        .and(warp::get())
+        .and(warp::query::<SearchQuery>())
+        .and(store_filter.clone())
+        .and_then(handle_api_inbox_emails);
+
+    let api_email_detail = warp::path!("api" / "emails" / String)
         .and(warp::get())
         .and(store_filter.clone())
         .and_then(handle_api_emails_detail);
 
+    // API: GET /api/emails/{id}/headers → 指定したメールのヘッダーを受信した順に返す
+    let api_email_headers = warp::path!("api" / "emails" / String / "headers")
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and_then(handle_api_email_headers);
+
+    // API: GET /api/emails/{id}/parts → 指定したメールのMIMEパートの木構造を返す
+    let api_email_parts = warp::path!("api" / "emails" / String / "parts")
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and_then(handle_api_email_parts);
+
+    // API: GET /api/emails/{id}/parts/{part_id} → 指定したパートの内容(デコード済み)を返す
+    let api_email_part = warp::path!("api" / "emails" / String / "parts" / String)
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and_then(handle_api_email_part);
+
+    // API: GET /api/emails/{id}/html → HTML本文をサニタイズし、サンドボックス表示用に返す
+    let api_email_html = warp::path!("api" / "emails" / String / "html")
+        .and(warp::get())
+        .and(warp::query::<HtmlPreviewQuery>())
+        .and(store_filter.clone())
+        .and_then(handle_api_email_html);
+
+    // API: GET /api/emails/{id}/cid/{content-id} → HTMLからcid:で参照されるインラインパートを返す
+    let api_email_cid = warp::path!("api" / "emails" / String / "cid" / String)
+        .and(warp::get())
+        .and(store_filter.clone())
+        .and_then(handle_api_email_cid);
+
     // API: DELETE /api/emails/{id} → 指定 id のメールを削除
-    let api_email_delete = warp::path!("api" / "emails" / usize)
+    let api_email_delete = warp::path!("api" / "emails" / String)
         .and(warp::delete())
         .and(store_filter.clone())
         .and_then(handle_api_email_delete);
@@ -49,10 +138,48 @@ pub async fn run_http_server(
         .and(store_filter.clone())
         .and_then(handle_api_delete_batch);
 
-    let api_attachement_download = warp::path!("api" / "emails" / "download" / String)
+    // API: GET /api/emails/{id}/attachments/{ファイル名} → 指定したメールの添付ファイルをダウンロードさせる
+    let api_email_attachment = warp::path!("api" / "emails" / String / "attachments" / String)
         .and(warp::get())
         .and(store_filter.clone())
-        .and_then(handle_api_download);
+        .and_then(handle_api_email_attachment);
+
+    // API: GET /api/emails/export/mbox → メールをmbox形式でエクスポート(?q= で絞り込み)
+    let api_export_mbox = warp::path!("api" / "emails" / "export" / "mbox")
+        .and(warp::get())
+        .and(warp::query::<SearchQuery>())
+        .and(store_filter.clone())
+        .and_then(handle_api_export_mbox);
+
+    // API: GET /api/emails/export/maildir → メールをzip圧縮したMaildirでエクスポート(?q= で絞り込み)
+    let api_export_maildir = warp::path!("api" / "emails" / "export" / "maildir")
+        .and(warp::get())
+        .and(warp::query::<SearchQuery>())
+        .and(store_filter.clone())
+        .and_then(handle_api_export_maildir);
+
+    // API: POST /api/emails/import/{mbox|eml} → リクエストボディのメールを取り込む
+    let api_import = warp::path!("api" / "emails" / "import" / String)
+        .and(warp::post())
+        .and(warp::body::content_length_limit(IMPORT_BODY_LIMIT))
+        .and(warp::body::bytes())
+        .and(store_filter.clone())
+        .and(with_ws_tx(ws_tx.clone()))
+        .and_then(handle_api_import);
+
+    // API: GET /api/tls/certificate → テストクライアントに信頼させる証明書(PEM)をダウンロード
+    let tls_filter = warp::any().map(move || tls.clone());
+    let api_tls_certificate = warp::path!("api" / "tls" / "certificate")
+        .and(warp::get())
+        .and(tls_filter.clone())
+        .and_then(handle_api_tls_certificate);
+
+    // API: POST /api/tls/reload → 証明書・秘密鍵のファイルを読み込み直す(管理用)
+    let api_tls_reload = warp::path!("api" / "tls" / "reload")
+        .and(warp::post())
+        .and(http_auth::with_admin(http_config.get_admin_token().clone()))
+        .and(tls_filter.clone())
+        .map(handle_api_tls_reload);
 
     let ws_route = warp::path("ws")
         .and(warp::ws())
@@ -71,20 +198,42 @@ pub async fn run_http_server(
         .or(api_email)
         .or(api_email_delete)
         .or(api_email_detail)
+        .or(api_email_headers)
+        .or(api_email_parts)
+        .or(api_email_part)
+        .or(api_email_html)
+        .or(api_email_cid)
         .or(api_emails_clear)
-        .or(api_attachement_download)
+        .or(api_email_attachment)
+        .or(api_export_mbox)
+        .or(api_export_maildir)
+        .or(api_import)
+        .or(api_users)
+        .or(api_user_emails)
+        .or(api_inboxes)
+        .or(api_inbox_emails)
+        .or(api_tls_certificate)
+        .or(api_tls_reload)
         .or(ws_route)
+        .recover(http_auth::handle_rejection)
         .with(cors);
 
-    // ポート 8025 で HTTP サーバーを起動
-    warp::serve(routes).run(([127, 0, 0, 1], 8025)).await;
+    // 設定したアドレスで HTTP サーバーを起動(ホスト名の場合は名前解決する)
+    let addr = tokio::net::lookup_host(http_config.get_addr())
+        .await?
+        .next()
+        .with_context(|| format!("{} を名前解決できません", http_config.get_addr()))?;
+    info!("Http Server(Web UI) running on {} ...", addr);
+    warp::serve(routes).run(addr).await;
 
-    info!("Http Server(Web UI) running on 127.0.0.1:8025 ...");
     Ok(())
 }
 /// Web UI のルートハンドラ：受信メール一覧を HTML で返す
-async fn handle_index(email_store: EmailStore) -> Result<impl warp::Reply, warp::Rejection> {
-    match tokio::fs::read_to_string("static/index.html").await {
+async fn handle_index(
+    index_html: PathBuf,
+    email_store: EmailStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match tokio::fs::read_to_string(&index_html).await {
         Ok(contents) => Ok(warp::reply::html(
             http_html_service::init_html(email_store, contents).await,
         )),
@@ -100,26 +249,74 @@ async fn handle_api_emails_get(
     search_query: SearchQuery,
     email_store: EmailStore,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let store = email_store.0.lock().await;
+    let store = email_store.0.list().await.map_err(storage_error)?;
     let emails: Vec<EmailSummary> = store
         .iter()
-        .enumerate()
-        .filter(|(_, email)| {
-            if let Some(query) = search_query.get_q() {
-                let query_lower = query.to_lowercase();
-                return email
-                    .get_subject()
-                    .as_ref()
-                    .map_or(false, |s| s.to_lowercase().contains(&query_lower))
-                    || email
-                        .get_from()
-                        .as_ref()
-                        .map_or(false, |s| s.to_lowercase().contains(&query_lower))
-                    || email.get_body().to_lowercase().contains(&query_lower);
-            }
-            true
-        })
-        .map(|(i, email)| email.convert_to_email_summary(i))
+        .filter(|email| search_query.matches(email))
+        .map(|email| email.convert_to_email_summary())
+        .collect();
+
+    Ok(warp::reply::json(&emails))
+}
+
+/// API ハンドラ：GET /api/users → メールを送信したユーザーの一覧を返す
+async fn handle_api_users(email_store: EmailStore) -> Result<impl warp::Reply, warp::Rejection> {
+    let store = email_store.0.list().await.map_err(storage_error)?;
+    let mut users: Vec<String> = store
+        .iter()
+        .filter_map(|email| email.get_envelope().get_auth_user().clone())
+        .collect();
+    users.sort();
+    users.dedup();
+
+    Ok(warp::reply::json(&users))
+}
+
+/// API ハンドラ：GET /api/users/{name}/emails → 指定したユーザーが送信したメールを返す
+async fn handle_api_user_emails(
+    username: String,
+    search_query: SearchQuery,
+    email_store: EmailStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // パスの値はURLエンコードされたまま渡ってくる(alice%40example.com 等)
+    let username = percent_decode_str(&username)
+        .decode_utf8_lossy()
+        .to_string();
+    let store = email_store.0.list().await.map_err(storage_error)?;
+    let emails: Vec<EmailSummary> = store
+        .iter()
+        .filter(|email| email.is_sent_by(&username) && search_query.matches(email))
+        .map(|email| email.convert_to_email_summary())
+        .collect();
+
+    Ok(warp::reply::json(&emails))
+}
+
+/// API ハンドラ：GET /api/inboxes → 受信したことのある宛先アドレスの一覧を返す
+async fn handle_api_inboxes(email_store: EmailStore) -> Result<impl warp::Reply, warp::Rejection> {
+    let store = email_store.0.list().await.map_err(storage_error)?;
+    let mut inboxes: Vec<String> = store
+        .iter()
+        .flat_map(|email| email.get_inbox_addresses())
+        .collect();
+    inboxes.sort();
+    inboxes.dedup();
+
+    Ok(warp::reply::json(&inboxes))
+}
+
+/// API ハンドラ：GET /api/inboxes/{address}/emails → 指定したアドレス宛てのメールを返す
+async fn handle_api_inbox_emails(
+    address: String,
+    search_query: SearchQuery,
+    email_store: EmailStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let address = percent_decode_str(&address).decode_utf8_lossy().to_string();
+    let store = email_store.0.list().await.map_err(storage_error)?;
+    let emails: Vec<EmailSummary> = store
+        .iter()
+        .filter(|email| email.is_delivered_to(&address) && search_query.matches(email))
+        .map(|email| email.convert_to_email_summary())
         .collect();
 
     Ok(warp::reply::json(&emails))
@@ -127,26 +324,184 @@ async fn handle_api_emails_get(
 
 /// API ハンドラ：GET /api/emails/{id} → 指定したメールの詳細を返す
 async fn handle_api_emails_detail(
-    id: usize,
+    id: String,
     email_store: EmailStore,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let store = email_store.0.lock().await;
-    if let Some(email) = store.get(id) {
-        let email_summary = email.convert_to_email_summary(id);
+    let email = email_store.0.get(&id).await.map_err(storage_error)?;
+    if let Some(email) = email {
+        let email_summary = email.convert_to_email_summary();
         Ok(warp::reply::json(&email_summary))
     } else {
         Err(warp::reject::not_found())
     }
 }
 
+/// API ハンドラ：GET /api/emails/{id}/headers → ヘッダーの一覧(名前・デコード後の値・受信したままの値)を返す
+async fn handle_api_email_headers(
+    id: String,
+    email_store: EmailStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match email_store.0.get(&id).await.map_err(storage_error)? {
+        Some(email) => Ok(warp::reply::json(email.get_headers())),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+/// API ハンドラ：GET /api/emails/{id}/parts → MIMEパートの木構造を返す
+async fn handle_api_email_parts(
+    id: String,
+    email_store: EmailStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let email = email_store.0.get(&id).await.map_err(storage_error)?;
+    match email.as_ref().and_then(|email| email.get_parts().as_ref()) {
+        Some(parts) => Ok(warp::reply::json(parts)),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+/// API ハンドラ：GET /api/emails/{id}/parts/{part_id} → パートの内容を返す
+///
+/// HTMLのパートをそのまま開いてもスクリプトが動かないように、CSPのsandboxを付けて返す
+async fn handle_api_email_part(
+    id: String,
+    part_id: String,
+    email_store: EmailStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    use warp::http::header::{
+        HeaderValue, CONTENT_DISPOSITION, CONTENT_SECURITY_POLICY, CONTENT_TYPE,
+        X_CONTENT_TYPE_OPTIONS,
+    };
+    let email = email_store.0.get(&id).await.map_err(storage_error)?;
+    let Some(part) = email
+        .as_ref()
+        .and_then(|email| email.get_parts().as_ref())
+        .and_then(|parts| parts.find(&part_id))
+    else {
+        return Err(warp::reject::not_found());
+    };
+
+    let content_type = if part.get_content_type().starts_with("text/") {
+        format!(
+            "{}; charset={}",
+            part.get_content_type(),
+            part.get_charset()
+        )
+    } else {
+        part.get_content_type().clone()
+    };
+    let mut response = warp::reply::Response::new(part.get_data().as_ref().clone().into());
+    let headers = response.headers_mut();
+    if let Ok(value) = content_type.parse() {
+        headers.insert(CONTENT_TYPE, value);
+    }
+    if let Some(filename) = part.get_filename() {
+        if let Ok(value) = content_disposition("inline", Some(filename)).parse() {
+            headers.insert(CONTENT_DISPOSITION, value);
+        }
+    }
+    headers.insert(CONTENT_SECURITY_POLICY, HeaderValue::from_static("sandbox"));
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    Ok(response)
+}
+
+/// ## Summary
+/// API ハンドラ：GET /api/emails/{id}/cid/{content-id} → Content-IDで参照されるパートを返す
+///
+/// ## Note
+/// Content-Typeは送信者が自由に付けられるので、ラスター画像だけをインラインで返し、
+/// それ以外(SVG・HTML等)はダウンロードさせる
+/// どちらの場合もWeb UIのオリジンでスクリプトが動かないよう `sandbox` のCSPを付ける
+async fn handle_api_email_cid(
+    id: String,
+    content_id: String,
+    email_store: EmailStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    use warp::http::header::{
+        HeaderValue, CONTENT_DISPOSITION, CONTENT_SECURITY_POLICY, CONTENT_TYPE,
+        X_CONTENT_TYPE_OPTIONS,
+    };
+    // Content-IDは `@` 等を含むのでURLエンコードされて渡ってくる
+    let content_id = percent_decode_str(&content_id)
+        .decode_utf8_lossy()
+        .to_string();
+    let email = email_store.0.get(&id).await.map_err(storage_error)?;
+    let Some(part) = email
+        .as_ref()
+        .and_then(|email| email.find_by_content_id(&content_id))
+    else {
+        return Err(warp::reject::not_found());
+    };
+
+    let mut response = warp::reply::Response::new(part.get_data().as_ref().clone().into());
+    let headers = response.headers_mut();
+    if mime::is_inline_image(part.get_content_type()) {
+        if let Ok(value) = part.get_content_type().parse() {
+            headers.insert(CONTENT_TYPE, value);
+        }
+    } else {
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        let disposition = content_disposition("attachment", part.get_filename().as_deref());
+        if let Ok(value) = disposition.parse() {
+            headers.insert(CONTENT_DISPOSITION, value);
+        }
+    }
+    headers.insert(CONTENT_SECURITY_POLICY, HeaderValue::from_static("sandbox"));
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    Ok(response)
+}
+
+/// ## Summary
+/// API ハンドラ：GET /api/emails/{id}/html → HTML本文をサニタイズして返す
+///
+/// ## Note
+/// Web UIのサンドボックス化したiframeで表示する想定
+/// HTML本文がなければテキストの本文を表示する
+/// ブロックしたリモート画像の数を `X-Blocked-Remote-Images` ヘッダーで返す
+async fn handle_api_email_html(
+    id: String,
+    query: HtmlPreviewQuery,
+    email_store: EmailStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    use warp::http::header::{
+        HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, CONTENT_TYPE, REFERRER_POLICY,
+        X_CONTENT_TYPE_OPTIONS,
+    };
+    let Some(email) = email_store.0.get(&id).await.map_err(storage_error)? else {
+        return Err(warp::reject::not_found());
+    };
+    let allow_remote_images = *query.get_remote_images();
+    let preview = match email.get_resolved_html() {
+        Some(html) => HtmlPreview::from_html(&html, allow_remote_images),
+        None => HtmlPreview::from_text(email.get_text().as_deref().unwrap_or_default()),
+    };
+
+    let mut response = warp::reply::Response::new(preview.get_document().clone().into());
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    if let Ok(value) = html_preview::content_security_policy(allow_remote_images).parse() {
+        headers.insert(CONTENT_SECURITY_POLICY, value);
+    }
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    headers.insert(REFERRER_POLICY, HeaderValue::from_static("no-referrer"));
+    headers.insert(
+        HeaderName::from_static("x-blocked-remote-images"),
+        HeaderValue::from(*preview.get_blocked_remote_images()),
+    );
+    Ok(response)
+}
+
 /// API ハンドラ：DELETE /api/emails/{id} → 指定したメールを削除する
 async fn handle_api_email_delete(
-    id: usize,
+    id: String,
     email_store: EmailStore,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut store = email_store.0.lock().await;
-    if id < store.len() {
-        store.remove(id);
+    if email_store.0.delete(&id).await.map_err(storage_error)? {
         Ok(warp::reply::with_status(
             "Delete",
             warp::http::StatusCode::OK,
@@ -160,50 +515,190 @@ async fn handle_api_email_delete(
 async fn handle_api_delete_batch(
     email_store: EmailStore,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut store = email_store.0.lock().await;
-    store.clear();
+    email_store.0.clear().await.map_err(storage_error)?;
     Ok(warp::reply::with_status(
         "Clean",
         warp::http::StatusCode::OK,
     ))
 }
 
-/// API ハンドラ：GET /api/emails/download/{ファイル名} → ファイルをdownloadする
-async fn handle_api_download(
+/// API ハンドラ：GET /api/emails/{id}/attachments/{ファイル名} → 添付ファイルをダウンロードさせる
+///
+/// ファイル名は同じメールの添付ファイルの中からだけ探す
+async fn handle_api_email_attachment(
+    id: String,
     filename: String,
     email_store: EmailStore,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    use warp::http::header::CONTENT_DISPOSITION;
-    let store = email_store.0.lock().await;
-    let attachments = store
-        .iter()
-        .map(|e| e.get_attachments())
-        .flatten()
-        .collect::<Vec<&AttachmentData>>();
-
-    let target_download_file = attachments.iter().find(|attachment| {
-        if let Some(target_filename) = attachment.get_filename() {
-            return target_filename == &filename;
-        } else {
-            return false;
+    // ファイル名はURLエンコードされて渡ってくる
+    let filename = percent_decode_str(&filename)
+        .decode_utf8_lossy()
+        .to_string();
+    let email = email_store.0.get(&id).await.map_err(storage_error)?;
+    let Some(attachment) = email
+        .as_ref()
+        .and_then(|email| email.find_attachment(&filename))
+    else {
+        return Err(warp::reject::not_found());
+    };
+
+    // TODO 内部データcloneはファイルサイズが巨大な場合負荷が大きい
+    Ok(attachment_response(
+        attachment.get_data_arc().as_ref().clone(),
+        "application/octet-stream",
+        &filename,
+    ))
+}
+
+/// API ハンドラ：GET /api/emails/export/mbox → mbox形式でダウンロードさせる
+async fn handle_api_export_mbox(
+    search_query: SearchQuery,
+    email_store: EmailStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let emails = search_emails(&search_query, &email_store).await?;
+    Ok(attachment_response(
+        mail_io::export_mbox(&emails),
+        "application/mbox",
+        "emails.mbox",
+    ))
+}
+
+/// API ハンドラ：GET /api/emails/export/maildir → Maildirをzipでダウンロードさせる
+async fn handle_api_export_maildir(
+    search_query: SearchQuery,
+    email_store: EmailStore,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let emails = search_emails(&search_query, &email_store).await?;
+    let zip = mail_io::export_maildir_zip(&emails).map_err(storage_error)?;
+    Ok(attachment_response(zip, "application/zip", "maildir.zip"))
+}
+
+/// API ハンドラ：POST /api/emails/import/{mbox|eml} → メールを取り込んで採番したIDを返す
+async fn handle_api_import(
+    format: String,
+    body: warp::hyper::body::Bytes,
+    email_store: EmailStore,
+    ws_tx: broadcast::Sender<String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let emails = match format.as_str() {
+        "mbox" => mail_io::import_mbox(&body),
+        "eml" => vec![mail_io::import_eml(&body)],
+        _ => return Err(warp::reject::not_found()),
+    };
+
+    let mut ids = vec![];
+    for email in emails {
+        ids.push(email.get_id().clone());
+        email_store.0.save(email).await.map_err(storage_error)?;
+    }
+    info!("{} 件のメールをインポートしました", ids.len());
+    let _ = ws_tx.send(WebSocketCommand::Update.into());
+
+    Ok(warp::reply::json(&ids))
+}
+
+/// API ハンドラ：GET /api/tls/certificate → 証明書(PEM)をダウンロードさせる
+///
+/// 自己署名証明書を生成した場合はCA証明書を返すので、クライアントの信頼するCAに追加して使う
+async fn handle_api_tls_certificate(
+    tls: Arc<ReloadableTls>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match tls.trust_pem() {
+        Some(pem) => Ok(attachment_response(
+            pem.into_bytes(),
+            "application/x-pem-file",
+            "rust-mail-ca.pem",
+        )),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+/// API ハンドラ：POST /api/tls/reload → 証明書を再読み込みする
+///
+/// 呼び出し元は `http_auth::with_admin` で確認済み
+/// 失敗した場合は500とエラー内容を返す(それまでの証明書をそのまま使い続ける)
+fn handle_api_tls_reload(tls: Arc<ReloadableTls>) -> impl warp::Reply {
+    match tls.reload() {
+        Ok(()) => warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "reloaded": true })),
+            warp::http::StatusCode::OK,
+        ),
+        Err(e) => {
+            error!("TLS証明書の再読み込みに失敗しました: {:#}", e);
+            warp::reply::with_status(
+                warp::reply::json(
+                    &serde_json::json!({ "reloaded": false, "error": format!("{:#}", e) }),
+                ),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )
         }
-    });
+    }
+}
 
-    if let Some(download_file) = target_download_file {
-        let data_arc = download_file.get_data_arc();
-        // TODO 内部データcloneはファイルサイズが巨大な場合負荷が大きい
-        let body = warp::hyper::Body::from(data_arc.as_ref().clone());
-        Ok(warp::reply::with_header(
-            warp::reply::Response::new(body),
-            CONTENT_DISPOSITION,
-            format!(
-                "attachment; filename=\"{}\"",
-                download_file.get_filename().as_ref().unwrap()
-            ),
-        ))
-    } else {
-        Err(warp::reject::not_found())
+/// 検索条件に一致するメールを取得する
+async fn search_emails(
+    search_query: &SearchQuery,
+    email_store: &EmailStore,
+) -> Result<Vec<EmailData>, warp::Rejection> {
+    let store = email_store.0.list().await.map_err(storage_error)?;
+    Ok(store
+        .into_iter()
+        .filter(|email| search_query.matches(email))
+        .collect())
+}
+
+/// ファイルとしてダウンロードさせるレスポンスを作成する
+fn attachment_response(body: Vec<u8>, content_type: &str, filename: &str) -> warp::reply::Response {
+    use warp::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+    let mut response = warp::reply::Response::new(body.into());
+    let headers = response.headers_mut();
+    if let Ok(value) = content_type.parse() {
+        headers.insert(CONTENT_TYPE, value);
+    }
+    if let Ok(value) = content_disposition("attachment", Some(filename)).parse() {
+        headers.insert(CONTENT_DISPOSITION, value);
     }
+    response
+}
+
+/// ## Summary
+/// Content-Dispositionヘッダーの値を作成する(RFC 6266)
+///
+/// ## Parameters
+/// - `disposition`: `attachment` / `inline`
+/// - `filename`: ファイル名(送信者が自由に付けられる値)
+///
+/// ## Note
+/// `filename=` にはASCII以外の文字・`"`・`\`・制御文字を `_` に置き換えた値を入れ、
+/// 元のファイル名は `filename*=UTF-8''…` にパーセントエンコードして入れる
+fn content_disposition(disposition: &str, filename: Option<&str>) -> String {
+    let Some(filename) = filename else {
+        return disposition.to_string();
+    };
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition,
+        fallback,
+        utf8_percent_encode(filename, ATTR_CHAR)
+    )
+}
+
+/// ストレージの読み書きに失敗した場合のRejection(500として扱われる)
+#[derive(Debug)]
+struct StorageError;
+
+impl warp::reject::Reject for StorageError {}
+
+fn storage_error(e: anyhow::Error) -> warp::Rejection {
+    error!("storage error: {}", e);
+    warp::reject::custom(StorageError)
 }
 
 fn with_ws_tx(
@@ -227,3 +722,22 @@ async fn handle_ws_connection(
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_disposition_encodes_filename() {
+        assert_eq!(content_disposition("inline", None), "inline");
+        assert_eq!(
+            content_disposition("attachment", Some("日本.txt")),
+            "attachment; filename=\"__.txt\"; filename*=UTF-8''%E6%97%A5%E6%9C%AC.txt"
+        );
+        // 引用符や改行でヘッダーを壊せない
+        assert_eq!(
+            content_disposition("attachment", Some("a\"b\r\n.txt")),
+            "attachment; filename=\"a_b__.txt\"; filename*=UTF-8''a%22b%0D%0A.txt"
+        );
+    }
+}
@@ -1,2 +1,4 @@
+mod html_preview;
+mod http_auth;
 mod http_html_service;
 pub mod http_server;
@@ -0,0 +1,230 @@
+use encoding_rs::Encoding;
+use log::debug;
+use mailparse::{MailAddr, MailHeader};
+use percent_encoding::percent_decode_str;
+use rumbok::Getter;
+use serde::Serialize;
+
+/// メールヘッダーの1件分(受信した順に保持する)
+#[derive(Clone, Debug, Getter, Serialize)]
+pub struct HeaderField {
+    name: String,
+    /// encoded-word(RFC 2047)をデコードし、折り返しを解除した値
+    value: String,
+    /// 受信したままの値(折り返しも含む)
+    raw: String,
+}
+
+impl HeaderField {
+    pub fn from_header(header: &MailHeader) -> Self {
+        Self {
+            name: header.get_key(),
+            value: header.get_value(),
+            raw: String::from_utf8_lossy(header.get_value_raw()).to_string(),
+        }
+    }
+}
+
+/// アドレスヘッダーの1件分(表示名 + アドレス)
+#[derive(Clone, Debug, Getter, Serialize)]
+pub struct Mailbox {
+    /// 表示名(encoded-wordはデコード済み)
+    name: Option<String>,
+    address: String,
+    /// グループ構文(`group: a@example.com, b@example.com;`)のグループ名
+    group: Option<String>,
+}
+
+/// ## Summary
+/// アドレスを持つヘッダーを解析した一覧
+///
+/// ## Note
+/// 同じヘッダーが複数ある場合はすべてのアドレスをまとめる
+#[derive(Clone, Debug, Default, Getter, Serialize)]
+pub struct AddressHeaders {
+    from: Vec<Mailbox>,
+    sender: Vec<Mailbox>,
+    reply_to: Vec<Mailbox>,
+    to: Vec<Mailbox>,
+    cc: Vec<Mailbox>,
+    bcc: Vec<Mailbox>,
+}
+
+impl AddressHeaders {
+    /// ヘッダーの一覧からアドレスを持つヘッダーを解析する
+    pub fn from_headers(headers: &[MailHeader]) -> Self {
+        let mut addresses = Self::default();
+        for header in headers {
+            let list = match header.get_key_ref().to_lowercase().as_str() {
+                "from" => &mut addresses.from,
+                "sender" => &mut addresses.sender,
+                "reply-to" => &mut addresses.reply_to,
+                "to" => &mut addresses.to,
+                "cc" => &mut addresses.cc,
+                "bcc" => &mut addresses.bcc,
+                _ => continue,
+            };
+            list.extend(parse_address_header(header));
+        }
+        addresses
+    }
+}
+
+/// ## Summary
+/// アドレスヘッダーを解析する
+///
+/// ## Note
+/// 表示名のencoded-word(RFC 2047)はデコードする
+/// 解析できない場合は空の一覧を返す
+pub fn parse_address_header(header: &MailHeader) -> Vec<Mailbox> {
+    let list = match mailparse::addrparse_header(header) {
+        Ok(list) => list,
+        Err(e) => {
+            debug!("{}ヘッダーを解析できません: {}", header.get_key_ref(), e);
+            return vec![];
+        }
+    };
+
+    let mut mailboxes = vec![];
+    for addr in list.iter() {
+        match addr {
+            MailAddr::Single(info) => {
+                mailboxes.push(Mailbox::new(info.display_name.as_deref(), &info.addr, None))
+            }
+            MailAddr::Group(group) => mailboxes.extend(group.addrs.iter().map(|info| {
+                Mailbox::new(
+                    info.display_name.as_deref(),
+                    &info.addr,
+                    Some(&group.group_name),
+                )
+            })),
+        }
+    }
+    mailboxes
+}
+
+impl Mailbox {
+    fn new(name: Option<&str>, address: &str, group: Option<&str>) -> Self {
+        let non_empty = |value: &str| Some(value.trim().to_string()).filter(|v| !v.is_empty());
+        Self {
+            name: name.and_then(non_empty),
+            address: address.trim().to_string(),
+            group: group.and_then(non_empty),
+        }
+    }
+}
+
+/// ## Summary
+/// Content-Type / Content-Dispositionのパラメータ値(ファイル名等)をデコードする
+///
+/// ## Note
+/// mailparseがデコードしないまま残した次の形式に対応する
+/// - RFC 2231の `charset'言語'%XX` 形式(`filename*=` で指定された値)
+/// - RFC 2047のencoded-word(`=?ISO-2022-JP?B?...?=`、仕様外だが日本のメーラーがよく送る)
+pub fn decode_parameter(value: &str) -> String {
+    if let Some(decoded) = decode_rfc2231(value) {
+        return decoded;
+    }
+    if value.contains("=?") {
+        // ヘッダーとして解析させるとencoded-wordがデコードされる
+        let header = format!("X: {}", value);
+        if let Ok((header, _)) = mailparse::parse_header(header.as_bytes()) {
+            return header.get_value();
+        }
+    }
+    value.to_string()
+}
+
+/// `charset'言語'%XX...` 形式の値をデコードする(形式が違う・未知の文字コードならNone)
+fn decode_rfc2231(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let (Some(charset), Some(_language), Some(encoded)) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    let encoding = Encoding::for_label(charset.trim().as_bytes())?;
+    let bytes: Vec<u8> = percent_decode_str(encoded).collect();
+    let (decoded, _, _) = encoding.decode(&bytes);
+    Some(decoded.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_rfc2231_parameters() {
+        assert_eq!(
+            decode_parameter("UTF-8''%E6%97%A5%E6%9C%AC.txt"),
+            "日本.txt"
+        );
+        assert_eq!(decode_parameter("utf-8'ja'a%20b.txt"), "a b.txt");
+        assert_eq!(decode_parameter("Shift_JIS''%93%FA%96%7B.txt"), "日本.txt");
+        // 未知の文字コードはそのまま返す
+        assert_eq!(
+            decode_parameter("x-unknown''%E6%97%A5.txt"),
+            "x-unknown''%E6%97%A5.txt"
+        );
+    }
+
+    #[test]
+    fn decodes_encoded_word_parameters() {
+        assert_eq!(
+            decode_parameter("=?ISO-2022-JP?B?GyRCJUYlOSVIGyhCLnR4dA==?="),
+            "テスト.txt"
+        );
+        assert_eq!(decode_parameter("=?UTF-8?Q?caf=C3=A9?="), "café");
+        assert_eq!(decode_parameter("report.pdf"), "report.pdf");
+    }
+
+    #[test]
+    fn parses_address_headers() {
+        let (headers, _) = mailparse::parse_headers(
+            b"From: =?UTF-8?B?5bGx55SwIOWkqumDjg==?= <yamada@example.com>\r\n\
+              To: a@example.com, team: b@example.com, \"C\" <c@example.com>;\r\n\
+              To: d@example.com\r\n\
+              Cc: not an address <\r\n\
+              Subject: test\r\n\r\n",
+        )
+        .unwrap();
+        let addresses = AddressHeaders::from_headers(&headers);
+
+        let from = &addresses.get_from()[0];
+        assert_eq!(from.get_name().as_deref(), Some("山田 太郎"));
+        assert_eq!(from.get_address(), "yamada@example.com");
+
+        // 同じヘッダーが複数あればまとめる
+        let to: Vec<(&str, Option<&str>)> = addresses
+            .get_to()
+            .iter()
+            .map(|mailbox| {
+                (
+                    mailbox.get_address().as_str(),
+                    mailbox.get_group().as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            to,
+            [
+                ("a@example.com", None),
+                ("b@example.com", Some("team")),
+                ("c@example.com", Some("team")),
+                ("d@example.com", None),
+            ]
+        );
+        assert!(addresses.get_cc().is_empty());
+        assert!(addresses.get_bcc().is_empty());
+    }
+
+    #[test]
+    fn header_field_keeps_raw_value() {
+        let (header, _) =
+            mailparse::parse_header(b"Subject: =?UTF-8?Q?caf=C3=A9?=\r\n folded\r\n").unwrap();
+        let field = HeaderField::from_header(&header);
+        assert_eq!(field.get_name(), "Subject");
+        assert_eq!(field.get_value(), "café folded");
+        assert_eq!(field.get_raw(), "=?UTF-8?Q?caf=C3=A9?=\r\n folded");
+    }
+}
@@ -1,28 +1,302 @@
+use std::{
+    io::{Cursor, Write},
+    path::Path,
+};
+
 use anyhow::Result;
-use chrono::Utc;
+use chrono::Local;
 use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{email::EmailData, envelope::Envelope};
 
-/// 受信したメールを保存
+/// 受信したメールをMaildirに保存
+///
+/// 一度 `tmp/` に書き込んでから `new/` に移動することで、
+/// 書き込み途中のファイルが読み込まれないようにする
 ///
 /// ## param
 ///
-/// * `datas` - 受け取った文字列
+/// * `maildir` - Maildirのルートディレクトリ
+/// * `file_name` - 保存するファイル名
+/// * `data` - メールの生データ
 ///
 /// ## return
 /// 成功したかどうか(Result)
-pub async fn save_data(datas: &Vec<String>) -> Result<()> {
-    // 受信メールを保存処理
+pub async fn save_data(maildir: &Path, file_name: &str, data: &[u8]) -> Result<()> {
+    let tmp_path = maildir.join("tmp").join(file_name);
     let mut file = OpenOptions::new()
         .create(true)
-        .append(true)
-        .open("./mails/email.txt")
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)
         .await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+
+    tokio::fs::rename(&tmp_path, maildir.join("new").join(file_name)).await?;
+    Ok(())
+}
 
-    let header = format!("---- New Email at {} ----", Utc::now());
-    file.write_all(header.as_bytes()).await?;
-    for data in datas {
-        file.write_all(data.as_bytes()).await?;
+/// mbox の区切り行("From " で始まる行)
+const MBOX_FROM_LINE: &[u8] = b"From ";
+
+/// ## Summary
+/// メールをmbox(mboxrd)形式に変換する
+///
+/// ## Parameters
+/// - `emails`: 出力するメール
+///
+/// ## Returns
+/// mbox形式のバイト列
+pub fn export_mbox(emails: &[EmailData]) -> Vec<u8> {
+    let mut mbox = vec![];
+    for email in emails {
+        let sender = match email.get_envelope().get_sender() {
+            Some(sender) if !sender.is_empty() => sender,
+            _ => "MAILER-DAEMON".to_string(),
+        };
+        let from_line = format!(
+            "From {} {}\n",
+            sender,
+            email.get_received_time().format("%a %b %e %H:%M:%S %Y")
+        );
+        mbox.extend_from_slice(from_line.as_bytes());
+
+        for line in email.get_raw().split_inclusive(|&b| b == b'\n') {
+            // 本文中の ">*From " で始まる行は ">" を1つ追加してエスケープする
+            let unquoted = trim_quote(line);
+            if unquoted.starts_with(MBOX_FROM_LINE) {
+                mbox.push(b'>');
+            }
+            mbox.extend_from_slice(line);
+        }
+        if !mbox.ends_with(b"\n") {
+            mbox.push(b'\n');
+        }
+        mbox.push(b'\n');
     }
+    mbox
+}
 
-    Ok(())
+/// ## Summary
+/// mbox形式のデータをメールごとに分割する
+///
+/// ## Parameters
+/// - `data`: mbox形式のバイト列
+///
+/// ## Returns
+/// (区切り行のエンベロープ送信者, メールの生データ) の一覧
+pub fn parse_mbox(data: &[u8]) -> Vec<(Option<String>, Vec<u8>)> {
+    let mut messages = vec![];
+    let mut current: Option<(Option<String>, Vec<u8>)> = None;
+
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        if line.starts_with(MBOX_FROM_LINE) {
+            if let Some((sender, raw)) = current.take() {
+                messages.push((sender, finish_mbox_message(raw)));
+            }
+            let sender = String::from_utf8_lossy(&line[MBOX_FROM_LINE.len()..])
+                .split_whitespace()
+                .next()
+                .filter(|sender| *sender != "MAILER-DAEMON")
+                .map(|sender| sender.to_string());
+            current = Some((sender, vec![]));
+            continue;
+        }
+
+        if let Some((_, raw)) = current.as_mut() {
+            // ">From " のエスケープを1段階戻す
+            if line.starts_with(b">") && trim_quote(line).starts_with(MBOX_FROM_LINE) {
+                raw.extend_from_slice(&line[1..]);
+            } else {
+                raw.extend_from_slice(line);
+            }
+        }
+    }
+    if let Some((sender, raw)) = current {
+        messages.push((sender, finish_mbox_message(raw)));
+    }
+
+    messages
+}
+
+/// ## Summary
+/// メールをMaildir形式(new/ cur/ tmp/)にしてzipにまとめる
+///
+/// ## Parameters
+/// - `emails`: 出力するメール
+///
+/// ## Returns
+/// zipファイルのバイト列
+pub fn export_maildir_zip(emails: &[EmailData]) -> Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(vec![]));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    for sub_dir in ["tmp", "new", "cur"] {
+        zip.add_directory(format!("maildir/{}/", sub_dir), options)?;
+    }
+    for email in emails {
+        zip.start_file(format!("maildir/new/{}", email.get_id()), options)?;
+        zip.write_all(email.get_raw())?;
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+/// ## Summary
+/// .mbox / .eml ファイルを読み込んでメールに変換する
+///
+/// ## Parameters
+/// - `path`: 読み込むファイル(拡張子が .mbox ならmbox、それ以外は1通のメールとして扱う)
+///
+/// ## Returns
+/// 読み込んだメール
+pub async fn import_file(path: &Path) -> Result<Vec<EmailData>> {
+    let data = tokio::fs::read(path).await?;
+    let is_mbox = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("mbox"));
+
+    if is_mbox {
+        Ok(import_mbox(&data))
+    } else {
+        Ok(vec![import_eml(&data)])
+    }
+}
+
+/// mbox形式のデータをメールに変換する
+pub fn import_mbox(data: &[u8]) -> Vec<EmailData> {
+    parse_mbox(data)
+        .into_iter()
+        .map(|(sender, raw)| {
+            let envelope = sender.map(Envelope::with_sender).unwrap_or_default();
+            EmailData::new(raw, envelope, Local::now())
+        })
+        .collect()
+}
+
+/// .eml(1通分のメール)をメールに変換する
+pub fn import_eml(data: &[u8]) -> EmailData {
+    EmailData::new(data.to_vec(), Envelope::default(), Local::now())
+}
+
+/// 行頭の ">" をすべて取り除く
+fn trim_quote(line: &[u8]) -> &[u8] {
+    let quote_len = line.iter().take_while(|&&b| b == b'>').count();
+    &line[quote_len..]
+}
+
+/// mboxの区切りとして付与された末尾の空行を取り除く
+fn finish_mbox_message(mut raw: Vec<u8>) -> Vec<u8> {
+    if raw.ends_with(b"\r\n\r\n") {
+        raw.truncate(raw.len() - 2);
+    } else if raw.ends_with(b"\n\n") {
+        raw.truncate(raw.len() - 1);
+    }
+    raw
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    const RAW: &[u8] =
+        b"From: a@example.com\r\nSubject: test\r\n\r\nFrom here\r\n>From there\r\nbody\r\n";
+
+    #[test]
+    fn mbox_round_trips() {
+        let emails = [
+            EmailData::new(
+                RAW.to_vec(),
+                Envelope::with_sender("a@example.com".to_string()),
+                Local::now(),
+            ),
+            // null senderはMAILER-DAEMONとして出力する
+            EmailData::new(
+                b"Subject: bounce\n\nno newline".to_vec(),
+                Envelope::default(),
+                Local::now(),
+            ),
+        ];
+        let mbox = export_mbox(&emails);
+        let text = String::from_utf8_lossy(&mbox);
+        assert!(text.starts_with("From a@example.com "), "{}", text);
+        assert!(
+            text.contains("\r\n>From here\r\n>>From there\r\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("\nFrom MAILER-DAEMON "), "{}", text);
+
+        let messages = parse_mbox(&mbox);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].0.as_deref(), Some("a@example.com"));
+        assert_eq!(messages[0].1, RAW);
+        assert_eq!(messages[1].0, None);
+        assert_eq!(messages[1].1, b"Subject: bounce\n\nno newline\n");
+    }
+
+    #[test]
+    fn imports_mbox_with_senders() {
+        let mbox = b"From a@example.com Sat Jan  1 00:00:00 2000\nSubject: 1\n\n>From body\n\n\
+                     From MAILER-DAEMON Sat Jan  1 00:00:00 2000\nSubject: 2\n\nbody\n\n";
+        let emails = import_mbox(mbox);
+        assert_eq!(emails.len(), 2);
+        assert_eq!(
+            emails[0].get_envelope().get_sender().as_deref(),
+            Some("a@example.com")
+        );
+        assert_eq!(emails[0].get_raw().as_slice(), b"Subject: 1\n\nFrom body\n");
+        assert_eq!(emails[1].get_envelope().get_sender(), None);
+        // 区切り行より前のデータは無視する
+        assert!(parse_mbox(b"garbage\n").is_empty());
+    }
+
+    #[test]
+    fn exports_maildir_zip() {
+        let email = EmailData::new(RAW.to_vec(), Envelope::default(), Local::now());
+        let zip = export_maildir_zip(std::slice::from_ref(&email)).unwrap();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(zip)).unwrap();
+        let message = format!("maildir/new/{}", email.get_id());
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort();
+        let mut expected = ["maildir/tmp/", "maildir/new/", "maildir/cur/", &message];
+        expected.sort();
+        assert_eq!(names, expected);
+        let mut raw = vec![];
+        archive
+            .by_name(&message)
+            .unwrap()
+            .read_to_end(&mut raw)
+            .unwrap();
+        assert_eq!(raw, RAW);
+    }
+
+    #[tokio::test]
+    async fn imports_files_by_extension() {
+        let dir =
+            std::env::temp_dir().join(format!("rust-mail-server-import-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mbox = dir.join("mail.MBOX");
+        std::fs::write(
+            &mbox,
+            b"From a@example.com Sat Jan  1 00:00:00 2000\nSubject: 1\n\nbody\n\n",
+        )
+        .unwrap();
+        let eml = dir.join("mail.eml");
+        std::fs::write(&eml, RAW).unwrap();
+
+        let from_mbox = import_file(&mbox).await.unwrap();
+        assert_eq!(from_mbox.len(), 1);
+        assert_eq!(from_mbox[0].get_raw().as_slice(), b"Subject: 1\n\nbody\n");
+        let from_eml = import_file(&eml).await.unwrap();
+        assert_eq!(from_eml.len(), 1);
+        assert_eq!(from_eml[0].get_raw().as_slice(), RAW);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
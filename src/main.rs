@@ -1,49 +1,156 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use anyhow::Result;
-use email::EmailData;
+use anyhow::{bail, Result};
+use auth::{
+    oauth::{LocalTokenValidator, TokenValidator},
+    user_db::{self, UserDatabase},
+};
+use cli::{ExportFormat, RunMode};
+use config::{tls::ReloadableTls, Config};
+use email::SearchQuery;
 use env_logger::Builder;
 use http::http_server;
+use log::info;
 use smtp_server::run_stmp_server;
-use tokio::sync::{broadcast, Mutex};
-use tokio_rustls::TlsAcceptor;
+use storage::{maildir::MaildirStorage, memory::MemoryStorage, MailStorage};
+use tokio::sync::broadcast;
 // https://qiita.com/simonritchie/items/87d3743e138763ff3e85
 mod auth;
+mod cli;
 mod command;
 mod config;
 mod constants;
 mod email;
+mod envelope;
 mod http;
+mod mail_header;
 mod mail_io;
+mod mime;
 mod smtp_server;
+mod smtp_session;
+mod storage;
 mod util;
-/// 共通のメールアドレスの型
+/// 共通のメールストアの型
 #[derive(Clone)]
-struct EmailStore(Arc<Mutex<Vec<EmailData>>>);
+struct EmailStore(Arc<dyn MailStorage>);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     logger_init();
 
-    // tls設定
-    let tls_config = config::load_tls_config();
-    let acceptor = tls_config.map(|tls| TlsAcceptor::from(tls));
+    let args = cli::parse_args(std::env::args().skip(1))?;
+    if let RunMode::HashPassword { password, scram } = &args.mode {
+        // ユーザーデータベースに書くパスワードハッシュを出力して終了
+        if *scram {
+            println!("{}", user_db::hash_scram_password(password));
+        } else {
+            println!("{}", user_db::hash_password(password)?);
+        }
+        return Ok(());
+    }
+    let config = Arc::new(Config::load(args.config_path.as_deref(), &args.overrides)?);
+    let is_export = matches!(args.mode, RunMode::Export { .. });
+    if is_export && config.get_storage().get_maildir().is_none() {
+        // メモリー上のストアは起動のたびに空なので、書き出すメールがない
+        bail!("export には書き出すメールを保存したMaildirを --maildir で指定してください");
+    }
 
-    // 受信メール保存する共通ストア(メモリー上)
-    let email_store = EmailStore(Arc::new(Mutex::new(Vec::new())));
+    // tls設定(SIGHUP・ファイルの更新・POST /api/tls/reload で再読み込みする)
+    let tls = Arc::new(ReloadableTls::new(config.get_tls().clone()));
+
+    // 受信メール保存する共通ストア(Maildir指定時はディスク、それ以外はメモリー上)
+    let email_store = match config.get_storage().get_maildir() {
+        Some(maildir) => EmailStore(Arc::new(MaildirStorage::open(maildir).await?)),
+        None => EmailStore(Arc::new(MemoryStorage::default())),
+    };
+
+    match args.mode {
+        RunMode::Export {
+            format,
+            output,
+            query,
+        } => {
+            // ストアのメールを書き出して終了
+            let search_query = SearchQuery::new(query);
+            let emails: Vec<_> = email_store
+                .0
+                .list()
+                .await?
+                .into_iter()
+                .filter(|email| search_query.matches(email))
+                .collect();
+            let data = match format {
+                ExportFormat::Mbox => mail_io::export_mbox(&emails),
+                ExportFormat::Maildir => mail_io::export_maildir_zip(&emails)?,
+            };
+            tokio::fs::write(&output, data).await?;
+            info!("{} 件のメールを {:?} に出力しました", emails.len(), &output);
+            return Ok(());
+        }
+        RunMode::HashPassword { .. } => unreachable!(),
+        RunMode::Serve { imports } => {
+            // 起動前にフィクスチャのメールを取り込む
+            for path in imports {
+                for email in mail_io::import_file(&path).await? {
+                    email_store.0.save(email).await?;
+                }
+                info!("{:?} のメールを取り込みました", &path);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(tls.clone().reload_on_sighup());
+    let watch_interval = *config.get_tls().get_watch_interval();
+    if watch_interval > 0 {
+        tokio::spawn(tls.clone().watch(Duration::from_secs(watch_interval)));
+    }
 
     // WebSocket用 broadcast チャネル
     let (ws_tx, _ws_rx) = broadcast::channel::<String>(100);
-    // SMTP サーバー（ポート 2525）を起動
+    // SMTP サーバーを起動
     let ws_tx_clone = ws_tx.clone();
     let smtp_sore = email_store.clone();
-    let smtp_server =
-        tokio::spawn(async move { run_stmp_server(smtp_sore, ws_tx_clone, acceptor).await });
+    let smtp_config = config.clone();
+    let smtp_tls = tls.clone();
+    let auth_config = config.get_auth();
+    let users = match auth_config.get_users_file() {
+        Some(path) => Some(Arc::new(UserDatabase::load(path)?)),
+        None => None,
+    };
+    // トークンの一覧・JWTの署名鍵のどちらかが指定された場合のみOAuthを有効にする
+    let token_validator: Option<Arc<dyn TokenValidator>> = if auth_config
+        .get_oauth_tokens_file()
+        .is_some()
+        || auth_config.get_oauth_jwt_secret().is_some()
+    {
+        Some(Arc::new(LocalTokenValidator::load(
+            auth_config.get_oauth_tokens_file().as_deref(),
+            auth_config.get_oauth_jwt_secret().clone(),
+        )?))
+    } else {
+        None
+    };
+    // HTTP APIもSMTP AUTHと同じユーザーデータベースで認証する
+    let http_users = users.clone();
+    let smtp_server = tokio::spawn(async move {
+        run_stmp_server(
+            smtp_config,
+            smtp_sore,
+            ws_tx_clone,
+            smtp_tls,
+            users,
+            token_validator,
+        )
+        .await
+    });
 
-    // HTTP サーバー（ポート 8025）を起動（Web UI 用）
+    // HTTP サーバーを起動（Web UI 用）
     let http_store = email_store.clone();
-    let http_server =
-        tokio::spawn(async move { http_server::run_http_server(http_store, ws_tx.clone()).await });
+    let http_config = config.clone();
+    let http_server = tokio::spawn(async move {
+        http_server::run_http_server(http_config, http_store, ws_tx.clone(), tls, http_users).await
+    });
 
     // 両方のサーバーが動作するのを待機
     smtp_server.await??;
@@ -0,0 +1,428 @@
+use std::sync::Arc;
+
+use log::error;
+use mailparse::{DispositionType, MailHeaderMap, ParsedContentDisposition, ParsedMail};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rumbok::Getter;
+use serde::Serialize;
+
+use crate::{
+    constants::{TEXT_HTML, TEXT_PLAIN},
+    email::AttachmentData,
+    mail_header,
+};
+
+/// URLのパスに埋め込む際にエンコードする文字(RFC 3986のunreserved以外)
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// `cid:` で参照されたパートのうち、そのまま画像として表示してよいContent-Type
+///
+/// SVG(スクリプトを含められる)やHTML等はここに含めず、ダウンロードさせる
+const INLINE_IMAGE_TYPES: [&str; 5] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/bmp",
+];
+
+/// ## Summary
+/// MIMEパートの木構造の1ノード
+///
+/// ## Note
+/// `part_id` はルートを `1`、その子を `1.1` `1.2`、孫を `1.2.1` のように付番する
+#[derive(Clone, Debug, Getter, Serialize)]
+pub struct MimePart {
+    part_id: String,
+    /// `text/plain` 等(小文字)
+    content_type: String,
+    charset: String,
+    /// `inline` / `attachment` 等(Content-Dispositionがなければ `inline`)
+    disposition: String,
+    filename: Option<String>,
+    /// Content-ID(`<` `>` は除く)
+    content_id: Option<String>,
+    /// デコード後のサイズ(バイト、multipartは0)
+    size: usize,
+    children: Vec<MimePart>,
+    /// デコード後の内容(multipartは空)
+    #[serde(skip)]
+    data: Arc<Vec<u8>>,
+}
+
+impl MimePart {
+    /// 指定した番号のパートを子孫から探す
+    pub fn find(&self, part_id: &str) -> Option<&MimePart> {
+        if self.part_id == part_id {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(part_id))
+    }
+}
+
+/// ## Summary
+/// メール全体のMIME構造を解析した結果
+///
+/// ## Note
+/// 入れ子の multipart(mixed > alternative > related 等)もすべてたどる
+/// 本文は最初に見つかった text/plain・text/html のパートを使う(添付ファイルを除く)
+/// Content-IDを持つinlineのパート(multipart/relatedの画像等)は添付ファイルではなくインラインパートとして扱う
+#[derive(Default)]
+pub struct MimeContent {
+    pub root: Option<MimePart>,
+    pub text: Option<String>,
+    pub html: Option<String>,
+    pub attachments: Vec<AttachmentData>,
+    pub inline_parts: Vec<AttachmentData>,
+}
+
+impl MimeContent {
+    pub fn parse(parsed: &ParsedMail) -> Self {
+        let mut content = Self::default();
+        content.root = Some(content.walk(parsed, "1".to_string()));
+        content
+    }
+
+    /// パートを再帰的にたどって木構造を作りながら本文・添付ファイルを集める
+    fn walk(&mut self, parsed: &ParsedMail, part_id: String) -> MimePart {
+        let content_type = parsed.ctype.mimetype.to_lowercase();
+        let disposition = parsed.get_content_disposition();
+        let filename = filename(parsed, &disposition);
+        let content_id = parsed
+            .headers
+            .get_first_value("Content-ID")
+            .map(|content_id| normalize_content_id(&content_id))
+            .filter(|content_id| !content_id.is_empty());
+
+        let mut children = vec![];
+        let mut data = Arc::new(vec![]);
+        if content_type.starts_with("multipart/") {
+            for (index, subpart) in parsed.subparts.iter().enumerate() {
+                children.push(self.walk(subpart, format!("{}.{}", &part_id, index + 1)));
+            }
+        } else {
+            match parsed.get_body_raw() {
+                Ok(body) => data = Arc::new(body),
+                Err(e) => error!("パート{}をデコードできません: {}", &part_id, e),
+            }
+
+            let is_attachment = disposition.disposition == DispositionType::Attachment;
+            if !is_attachment && (content_type == TEXT_PLAIN || content_type == TEXT_HTML) {
+                let body = if content_type == TEXT_PLAIN {
+                    &mut self.text
+                } else {
+                    &mut self.html
+                };
+                if body.is_none() {
+                    *body = parsed
+                        .get_body()
+                        .map_err(|e| error!("パート{}をデコードできません: {}", &part_id, e))
+                        .ok();
+                }
+            } else {
+                let part = AttachmentData::new(
+                    filename.clone(),
+                    content_type.clone(),
+                    content_id.clone(),
+                    data.clone(),
+                );
+                if !is_attachment && content_id.is_some() {
+                    self.inline_parts.push(part);
+                } else if is_attachment || filename.is_some() {
+                    self.attachments.push(part);
+                }
+            }
+        }
+
+        MimePart {
+            part_id,
+            content_type,
+            charset: parsed.ctype.charset.clone(),
+            disposition: disposition_name(&disposition.disposition),
+            filename,
+            content_id,
+            size: data.len(),
+            children,
+            data,
+        }
+    }
+}
+
+/// パートのファイル名(RFC 2231の `filename*=` や、Content-Typeの `name` しかない場合にも対応する)
+fn filename(parsed: &ParsedMail, disposition: &ParsedContentDisposition) -> Option<String> {
+    disposition
+        .params
+        .get("filename")
+        .or_else(|| disposition.params.get("filename*"))
+        .or_else(|| parsed.ctype.params.get("name"))
+        .map(|filename| mail_header::decode_parameter(filename))
+}
+
+/// ## Summary
+/// HTMLの本文からタグを取り除いたテキストを取り出す
+///
+/// ## Note
+/// 一覧の表示や検索に使うもので、script・styleの中身は含めず、文字参照(`&amp;` 等)はデコードする
+pub fn html_to_text(html: &str) -> String {
+    let text = ammonia::Builder::empty()
+        .clean_content_tags(["script", "style", "title"].into_iter().collect())
+        .clean(html)
+        .to_string();
+    htmlescape::decode_html(&text)
+        .unwrap_or(text)
+        .trim()
+        .to_string()
+}
+
+/// Content-Typeがインラインで表示してよいラスター画像か
+pub fn is_inline_image(content_type: &str) -> bool {
+    INLINE_IMAGE_TYPES.contains(&content_type.to_ascii_lowercase().as_str())
+}
+
+/// Content-IDの前後の空白と `<` `>` を取り除く
+pub fn normalize_content_id(content_id: &str) -> String {
+    content_id
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .trim()
+        .to_string()
+}
+
+/// ## Summary
+/// HTML中の画像の `cid:` 参照をインラインパートのAPI(`/api/emails/{id}/cid/{content-id}`)のURLに書き換える
+///
+/// ## Parameters
+/// - `html`: HTMLの本文
+/// - `email_id`: メールのID
+///
+/// ## Note
+/// 書き換えるのは `<img src="cid:...">` とCSSの `url(cid:...)` だけで、リンク(`<a href="cid:...">`)や本文中の文字列はそのまま残す
+/// `cid:` のURLはパーセントエンコードされている場合がある(RFC 2392)ので、デコードしてからエンコードし直す
+pub fn resolve_cid_references(html: &str, email_id: &str) -> String {
+    const SCHEME: &str = "cid:";
+    // ASCIIの小文字変換ではバイト位置が変わらないので、検索だけ小文字で行う
+    let lower = html.to_ascii_lowercase();
+    let mut resolved = String::with_capacity(html.len());
+    let mut rest = 0;
+    let mut search_from = 0;
+
+    while let Some(found) = lower[search_from..].find(SCHEME) {
+        let start = search_from + found;
+        let value_start = start + SCHEME.len();
+        search_from = value_start;
+        // `acid:` 等の単語の途中は対象外
+        if html[..start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_ascii_alphanumeric())
+            || !is_image_reference(&lower[..start])
+        {
+            continue;
+        }
+        let value_end = html[value_start..]
+            .find(|c: char| c == '"' || c == '\'' || c == ')' || c == '>' || c.is_whitespace())
+            .map_or(html.len(), |end| value_start + end);
+        if value_end == value_start {
+            continue;
+        }
+
+        let content_id = percent_decode_str(&html[value_start..value_end]).decode_utf8_lossy();
+        resolved.push_str(&html[rest..start]);
+        resolved.push_str(&format!(
+            "/api/emails/{}/cid/{}",
+            utf8_percent_encode(email_id, PATH_SEGMENT),
+            utf8_percent_encode(&normalize_content_id(&content_id), PATH_SEGMENT)
+        ));
+        rest = value_end;
+        search_from = value_end;
+    }
+    resolved.push_str(&html[rest..]);
+    resolved
+}
+
+/// `cid:` の直前(小文字化したもの)が `<img ... src=` か `url(` か
+fn is_image_reference(before: &str) -> bool {
+    let before = before.trim_end_matches(['"', '\'']).trim_end();
+    if before.ends_with("url(") {
+        return true;
+    }
+    let Some(before) = before.strip_suffix('=') else {
+        return false;
+    };
+    let Some(attribute_start) = before.trim_end().strip_suffix("src") else {
+        return false;
+    };
+    if !attribute_start.ends_with(char::is_whitespace) {
+        return false;
+    }
+    // タグの中(最後の `<` より後に `>` がない)で、そのタグがimgであること
+    let Some(tag_start) = attribute_start.rfind('<') else {
+        return false;
+    };
+    let tag = &attribute_start[tag_start + 1..];
+    !tag.contains('>')
+        && tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .is_some_and(|name| name == "img")
+}
+
+fn disposition_name(disposition: &DispositionType) -> String {
+    match disposition {
+        DispositionType::Inline => "inline".to_string(),
+        DispositionType::Attachment => "attachment".to_string(),
+        DispositionType::FormData => "form-data".to_string(),
+        DispositionType::Extension(name) => name.to_lowercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NESTED: &[u8] = b"Content-Type: multipart/mixed; boundary=\"mixed\"\r\n\
+\r\n\
+--mixed\r\n\
+Content-Type: multipart/alternative; boundary=\"alt\"\r\n\
+\r\n\
+--alt\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+\r\n\
+plain body\r\n\
+--alt\r\n\
+Content-Type: multipart/related; boundary=\"rel\"\r\n\
+\r\n\
+--rel\r\n\
+Content-Type: text/html; charset=utf-8\r\n\
+\r\n\
+<img src=\"cid:logo@example.com\">\r\n\
+--rel\r\n\
+Content-Type: image/png\r\n\
+Content-ID: <logo@example.com>\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+iVBORw0KGgo=\r\n\
+--rel--\r\n\
+--alt--\r\n\
+--mixed\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Disposition: attachment; filename*=UTF-8''%E6%97%A5%E6%9C%AC.txt\r\n\
+\r\n\
+attached text\r\n\
+--mixed\r\n\
+Content-Type: application/pdf; name=\"report.pdf\"\r\n\
+\r\n\
+%PDF\r\n\
+--mixed--\r\n";
+
+    #[test]
+    fn walks_nested_multipart() {
+        let parsed = mailparse::parse_mail(NESTED).unwrap();
+        let content = MimeContent::parse(&parsed);
+        let root = content.root.as_ref().unwrap();
+
+        let ids = |part: &MimePart| -> Vec<String> {
+            part.get_children()
+                .iter()
+                .map(|child| child.get_part_id().clone())
+                .collect()
+        };
+        assert_eq!(root.get_content_type(), "multipart/mixed");
+        assert_eq!(ids(root), ["1.1", "1.2", "1.3"]);
+        assert_eq!(ids(root.find("1.1").unwrap()), ["1.1.1", "1.1.2"]);
+        assert_eq!(ids(root.find("1.1.2").unwrap()), ["1.1.2.1", "1.1.2.2"]);
+
+        let image = root.find("1.1.2.2").unwrap();
+        assert_eq!(image.get_content_type(), "image/png");
+        assert_eq!(image.get_content_id().as_deref(), Some("logo@example.com"));
+        assert_eq!(*image.get_size(), 8);
+        assert_eq!(*root.get_size(), 0);
+        assert!(root.find("1.4").is_none());
+
+        // 添付ファイルのtext/plainは本文にしない
+        assert_eq!(
+            content.text.as_deref().map(str::trim_end),
+            Some("plain body")
+        );
+        assert_eq!(
+            content.html.as_deref().map(str::trim_end),
+            Some("<img src=\"cid:logo@example.com\">")
+        );
+        let inline: Vec<_> = content
+            .inline_parts
+            .iter()
+            .map(|part| part.get_content_id().as_deref())
+            .collect();
+        assert_eq!(inline, [Some("logo@example.com")]);
+        let attachments: Vec<_> = content
+            .attachments
+            .iter()
+            .map(|part| {
+                (
+                    part.get_filename().as_deref(),
+                    part.get_content_type().as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            attachments,
+            [
+                (Some("日本.txt"), "text/plain"),
+                (Some("report.pdf"), "application/pdf")
+            ]
+        );
+        assert_eq!(root.find("1.2").unwrap().get_disposition(), "attachment");
+        assert_eq!(root.find("1.3").unwrap().get_disposition(), "inline");
+    }
+
+    #[test]
+    fn normalizes_content_id() {
+        assert_eq!(
+            normalize_content_id(" <logo@example.com> "),
+            "logo@example.com"
+        );
+        assert_eq!(normalize_content_id("logo@example.com"), "logo@example.com");
+    }
+
+    #[test]
+    fn inline_image_allows_only_raster_images() {
+        assert!(is_inline_image("image/png"));
+        assert!(is_inline_image("IMAGE/JPEG"));
+        // スクリプトを含められる形式はインラインで返さない
+        assert!(!is_inline_image("image/svg+xml"));
+        assert!(!is_inline_image("text/html"));
+        assert!(!is_inline_image("application/xhtml+xml"));
+    }
+
+    #[test]
+    fn html_to_text_removes_markup() {
+        let html = r#"<html><head><title>t</title><style>p{color:red}</style></head>
+<body><p class="x">Tom &amp; Jerry</p><script>alert(1)</script></body></html>"#;
+        assert_eq!(html_to_text(html), "Tom & Jerry");
+    }
+
+    #[test]
+    fn resolve_cid_references_rewrites_image_sources() {
+        let html = r#"<IMG alt="x" SRC="cid:logo@example.com"><div style="background: url('cid:bg%40example.com')">"#;
+        assert_eq!(
+            resolve_cid_references(html, "id-1"),
+            r#"<IMG alt="x" SRC="/api/emails/id-1/cid/logo%40example.com"><div style="background: url('/api/emails/id-1/cid/bg%40example.com')">"#
+        );
+        assert_eq!(
+            resolve_cid_references("<img width=1 src = cid:a@b>", "id-1"),
+            "<img width=1 src = /api/emails/id-1/cid/a%40b>"
+        );
+    }
+
+    #[test]
+    fn resolve_cid_references_keeps_links_and_text() {
+        // リンクを書き換えると、同じオリジンのURLをサンドボックスの外で開けてしまう
+        let html = r#"<a href="cid:evil@x">open</a> see cid:evil@x <video src="cid:v@x"> acid:x"#;
+        assert_eq!(resolve_cid_references(html, "id-1"), html);
+    }
+}
@@ -1,346 +1,199 @@
-use std::io::ErrorKind;
+use std::sync::Arc;
 
-use anyhow::Result;
-use chrono::Local;
+use anyhow::{Context, Result};
 use log::{error, info, warn};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
     net::{TcpListener, TcpStream},
     sync::broadcast::Sender,
 };
-use tokio_rustls::{server::TlsStream, TlsAcceptor};
 
 use crate::{
-    auth::Auth,
-    command::{self, Command, WebSocketCommand},
-    constants::*,
-    email::EmailData,
-    util::base64,
+    auth::{oauth::TokenValidator, user_db::UserDatabase, AuthPolicy},
+    config::{
+        tls::{ClientCertificate, ReloadableTls},
+        Config,
+    },
+    smtp_session::{SessionContext, SessionEnd, SmtpSession},
     EmailStore,
 };
 
 pub async fn run_stmp_server(
+    config: Arc<Config>,
     email_store: EmailStore,
     ws_tx: Sender<String>,
-    acceptor: Option<TlsAcceptor>,
+    tls: Arc<ReloadableTls>,
+    users: Option<Arc<UserDatabase>>,
+    token_validator: Option<Arc<dyn TokenValidator>>,
 ) -> Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:2525").await?;
-    info!("SMTP Server is running on 127.0.0.1:2525 ...");
+    let smtp_config = config.get_smtp();
+    let listener = TcpListener::bind(smtp_config.get_addr()).await?;
+    info!("SMTP Server is running on {} ...", smtp_config.get_addr());
+
+    let auth_policy = AuthPolicy {
+        require_auth: *config.get_auth().get_require_auth(),
+        require_tls: *config.get_auth().get_require_tls(),
+        client_cert_auth: *config.get_auth().get_client_cert_auth(),
+    };
+
+    let context = SessionContext {
+        email_store,
+        ws_tx,
+        tls,
+        users,
+        token_validator,
+        auth_policy,
+        max_message_size: *smtp_config.get_max_message_size(),
+    };
+
+    // 暗黙TLS(SMTPS)用のリスナーを別ポートで起動
+    if let Some(smtps_addr) = smtp_config.get_smtps_addr() {
+        if context.tls.is_enabled() {
+            let smtps_listener = TcpListener::bind(smtps_addr).await?;
+            info!("SMTPS Server is running on {} ...", &smtps_addr);
+            tokio::spawn(run_smtps_listener(smtps_listener, context.clone()));
+        } else {
+            warn!("TLS 設定がないため SMTPS({}) は起動しません", &smtps_addr);
+        }
+    }
 
     loop {
         // 新しい接続を受け付ける
         let (socket, addr) = listener.accept().await?;
         info!("新しい接続先: {}", addr);
 
-        let store = email_store.clone();
-        let ws_tx_clone = ws_tx.clone();
-        let accptor_clone = acceptor.clone();
+        let context_clone = context.clone();
         // 接続ごとに別タスクで処理
         tokio::spawn(async move {
-            if let Err(e) = process_connection(socket, store, ws_tx_clone, accptor_clone).await {
+            if let Err(e) = process_connection(socket, context_clone).await {
                 error!("Error: {}", e);
             }
         });
     }
 }
 
-/// 1 つの SMTP 接続を処理する関数
-async fn process_connection(
-    socket: TcpStream,
-    email_store: EmailStore,
-    ws_tx: Sender<String>,
-    acceptor: Option<TlsAcceptor>,
-) -> Result<()> {
-    // 読み書き用にストリームを分割
-    //let (reader, mut writer) = socket.into_split();
-    let mut reader = BufReader::new(socket);
-
-    // クライアントに対して SMTP の挨拶を送信
-    reader
-        .get_mut()
-        .write_all(b"220 Rust SMTP Server Ready\r\n")
-        .await?;
-
-    // 認証状態を保持する
-    let mut auth = Auth::default();
-    let mut line = String::new();
-
+/// SMTPS(暗黙TLS)の接続を受け付ける
+///
+/// 接続直後にTLSハンドシェイクを行い、その後は通常のSMTPセッションとして処理する
+async fn run_smtps_listener(listener: TcpListener, context: SessionContext) {
     loop {
-        line.clear();
-
-        let bytes_read = reader.read_line(&mut line).await?;
-        if bytes_read == 0 {
-            // 接続がcloseされた場合
-            break;
-        }
-
-        let command = line.trim_end().to_uppercase();
-        match command::get_command_from_str(&command) {
-            Command::Helo => {
-                info!("[HELO] command is {}", &command);
-                reader.get_mut().write_all(b"250 Hello\r\n").await?;
-            }
-            Command::Ehlo => {
-                info!("[EHLO] command is {}", &command);
-                reader
-                    .get_mut()
-                    .write_all(b"250-MyRustSMTP\r\n250-STARTTLS\r\n250 AUTH LOGIN PLAIN\r\n")
-                    .await?;
-            }
-            Command::StartTls => {
-                if let Some(acceptor) = acceptor.clone() {
-                    info!("STARTTLS -> TLSへ切り替え成功");
-                    reader.get_mut().write_all(STARTTLS_MESSAGE_BYTES).await?;
-                    let s = reader.into_inner();
-                    let tls_socket = acceptor.accept(s).await?;
-                    handle_tls_client(tls_socket, email_store, ws_tx).await?;
-                    break;
-                } else {
-                    warn!("STARTTLSをサポートしていません");
-                    reader
-                        .get_mut()
-                        .write_all(STARTTLS_NO_SUPPORTED_MESSAGE_BYTES)
-                        .await?;
-                }
-            }
-            Command::MailFrom => {
-                reader.get_mut().write_all(OK_MESSAGE_BYTES).await?;
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("SMTPS accept error: {}", e);
+                continue;
             }
-            Command::RcptTo => {
-                reader.get_mut().write_all(OK_MESSAGE_BYTES).await?;
-            }
-            Command::Data => {
-                reader
-                    .get_mut()
-                    .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
-                    .await?;
-
-                let mut datas = vec![];
-                loop {
-                    line.clear();
-                    let bytes_read = reader.read_line(&mut line).await?;
-                    if bytes_read == 0 {
-                        // クライアントが切断
-                        break;
-                    }
-
-                    if line.trim_end() == "." {
-                        // 行にドットのみならデータ終了
-                        break;
-                    }
-
-                    datas.push(line.clone());
-                }
-
-                let email_content = datas.join("");
-                let mail_data = EmailData::new(email_content, Local::now());
-                // mutexをすぐ解放するための処置
-                {
-                    // 受信したメールを共有ストアに保存
-                    let mut store = email_store.0.lock().await;
-                    store.push(mail_data.clone());
-                }
-                // WebSocket 用に新着メール通知を送信
-                let _ = ws_tx.send(WebSocketCommand::Update.into());
-
-                reader.get_mut().write_all(b"250 Ok:queued\r\n").await?;
-            }
-            Command::Quit => {
-                reader.get_mut().write_all(b"221 Bye\r\n").await?;
-                break;
-            }
-            Command::AuthPlain => {
-                // AUTH PLAIN AHVzZXIAcGFzc3dvcmQ=   <-- 「\0user\0password」を base64 エンコードした文字列
-                let message_bytes = auth.parse_plain_credentials(&line);
-                reader.get_mut().write_all(message_bytes).await?;
-            }
-            Command::AuthLogin => {
-                // Username base64
-                reader.get_mut().write_all(b"334 VXNlcm5hbWU6\r\n").await?;
-                line.clear();
-                reader.read_line(&mut line).await?;
-                let username = match base64::decode(&line) {
-                    Ok(usr) => usr,
-                    Err(e) => {
-                        error!("{}", e);
-                        reader
-                            .get_mut()
-                            .write_all(INVALID_BASE64_MESSAGE_BYTES)
-                            .await?;
-                        continue;
-                    }
-                };
-
-                line.clear();
-                reader.get_mut().write_all(b"334 UGFzc3dvcmQ6\r\n").await?;
-                reader.read_line(&mut line).await?;
-                let password = match base64::decode(&line) {
-                    Ok(usr) => usr,
-                    Err(e) => {
-                        error!("{}", e);
-                        reader
-                            .get_mut()
-                            .write_all(INVALID_BASE64_MESSAGE_BYTES)
-                            .await?;
-                        continue;
-                    }
-                };
+        };
+        info!("新しい接続先(SMTPS): {}", addr);
 
-                info!("username:{} password:{}", &username, &password);
-                auth.set_authenticated(true);
-                auth.set_password(password);
-                auth.set_username(username);
-                reader
-                    .get_mut()
-                    .write_all(b"235 Authentication successful\r\n")
-                    .await?;
-            }
-            Command::Unknown => {
-                reader
-                    .get_mut()
-                    .write_all(b"500 Unrecongnized command\r\n")
-                    .await?;
+        let context_clone = context.clone();
+        tokio::spawn(async move {
+            if let Err(e) = process_tls_connection(socket, context_clone).await {
+                error!("Error: {}", e);
             }
-        }
+        });
     }
+}
 
+/// SMTPSの接続を処理する関数
+async fn process_tls_connection(socket: TcpStream, context: SessionContext) -> Result<()> {
+    // 接続ごとに取得するので、再読み込み後の接続には新しい証明書が使われる
+    let acceptor = context.tls.acceptor().context("TLS 設定がありません")?;
+    let tls_socket = acceptor.accept(socket).await?;
+    let client_cert = ClientCertificate::from_connection(tls_socket.get_ref().1);
+    let mut session = SmtpSession::new(tls_socket, context, true, client_cert);
+    session.greet().await?;
+    session.run().await?;
     Ok(())
 }
 
-async fn handle_tls_client(
-    socket: TlsStream<tokio::net::TcpStream>,
-    email_store: EmailStore,
-    ws_tx: Sender<String>,
-) -> Result<()> {
-    let (mut reader, mut writer) = tokio::io::split(socket);
-    let mut reader = BufReader::new(reader);
-    let mut line = String::new();
-    // 認証状態を保持する
-    let mut auth = Auth::default();
-    //send_tls(&mut writer, b"220 Rust SMTP(TLS) Server Ready\r\n").await?;
-    loop {
-        line.clear();
-        let n = read_tls(&mut reader, &mut line).await?;
-        if n == 0 {
-            break;
-        }
-
-        let command = line.trim_end().to_uppercase();
-        match command::get_command_from_str(&command) {
-            Command::Helo | Command::Ehlo => {
-                info!("[HELO EHLO] command is {}", &command);
-                //writer.write_all(b"250 Hello\r\n").await?;
-                send_tls(
-                    &mut writer,
-                    b"250-MyRustSMTP (TLS)\r\n250 AUTH LOGIN PLAIN\r\n",
-                )
+/// 1 つの SMTP 接続を処理する関数
+async fn process_connection(socket: TcpStream, context: SessionContext) -> Result<()> {
+    let mut session = SmtpSession::new(socket, context.clone(), false, None);
+    session.greet().await?;
+
+    if let SessionEnd::StartTls(socket) = session.run().await? {
+        // STARTTLSを受け付けたのでTLSに切り替えて新しいセッションとして続行する
+        if let Some(acceptor) = context.tls.acceptor() {
+            let tls_socket = acceptor.accept(socket).await?;
+            info!("STARTTLS -> TLSへ切り替え成功");
+            let client_cert = ClientCertificate::from_connection(tls_socket.get_ref().1);
+            SmtpSession::new(tls_socket, context, true, client_cert)
+                .run()
                 .await?;
-            }
-            Command::StartTls => {
-                warn!("既にTLS通信です");
-            }
-            Command::MailFrom => {
-                send_tls(&mut writer, OK_MESSAGE_BYTES).await?;
-            }
-            Command::RcptTo => {
-                send_tls(&mut writer, OK_MESSAGE_BYTES).await?;
-            }
-            Command::Data => {
-                send_tls(&mut writer, b"354 End data with <CR><LF>.<CR><LF>\r\n").await?;
-
-                let mut datas = vec![];
-                loop {
-                    line.clear();
-                    let bytes_read = read_tls(&mut reader, &mut line).await?;
-                    if bytes_read == 0 {
-                        // クライアントが切断
-                        break;
-                    }
-
-                    if line.trim_end() == "." {
-                        // 行にドットのみならデータ終了
-                        break;
-                    }
-
-                    datas.push(line.clone());
-                }
-
-                let email_content = datas.join("");
-                let mail_data = EmailData::new(email_content, Local::now());
-                // mutexをすぐ解放するための処置
-                {
-                    // 受信したメールを共有ストアに保存
-                    let mut store = email_store.0.lock().await;
-                    store.push(mail_data.clone());
-                }
-                // WebSocket 用に新着メール通知を送信
-                let _ = ws_tx.send(WebSocketCommand::Update.into());
-
-                send_tls(&mut writer, b"250 Ok:queued\r\n").await?;
-            }
-            Command::Quit => {
-                send_tls(&mut writer, b"221 Bye\r\n").await?;
-                break;
-            }
-            Command::AuthPlain => {
-                // AUTH PLAIN AHVzZXIAcGFzc3dvcmQ=   <-- 「\0user\0password」を base64 エンコードした文字列
-                let message_bytes = auth.parse_plain_credentials(&line);
-                send_tls(&mut writer, message_bytes).await?;
-            }
-            Command::AuthLogin => {}
-            Command::Unknown => {
-                send_tls(&mut writer, b"500 Unrecongnized command\r\n").await?;
-            }
         }
     }
-    Ok(())
-}
 
-/// ## Summary
-/// tlsで通信してメールを送る
-///
-/// ## Note
-/// ErrorKind::UnexpectedEofはrustlsだとERROR扱いになる
-/// 相手が close_notify を送らずに接続を閉じた時に起きるerror
-/// 一応このメールサーバーではclose_notifyがなくても正常終了とみなします。
-///
-/// ## Parameters
-/// - `writer`:
-/// - `msg_byte`:
-///
-/// ## Returns
-///
-/// ## Examples
-///```
-///
-///```
-async fn send_tls(writer: &mut WriteHalf<TlsStream<TcpStream>>, msg_byte: &[u8]) -> Result<()> {
-    if let Err(e) = writer.write_all(msg_byte).await {
-        if e.kind() == ErrorKind::UnexpectedEof {
-            // TLS的には “close_notify” が来ていないが、
-            // こちらとしては「相手が接続を閉じただけ」として扱う。
-            warn!("{}", e);
-            return Ok(());
-        }
-        return Err(e.into());
-    }
     Ok(())
 }
 
-async fn read_tls(
-    reader: &mut BufReader<ReadHalf<TlsStream<TcpStream>>>,
-    line: &mut String,
-) -> Result<usize> {
-    match reader.read_line(line).await {
-        Ok(result) => Ok(result),
-        Err(e) => {
-            if e.kind() == ErrorKind::UnexpectedEof {
-                // TLS的には “close_notify” が来ていないが、
-                // こちらとしては「相手が接続を閉じただけ」として扱う。
-                warn!("{}", e);
-                return Ok(0);
-            }
-            return Err(e.into());
+#[cfg(test)]
+mod tests {
+    use rustls::{
+        pki_types::{pem::PemObject, CertificateDer, ServerName},
+        ClientConfig, RootCertStore,
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        sync::broadcast,
+    };
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+    use crate::{
+        constants::{GREETING_MESSAGE_BYTES, MAX_MESSAGE_SIZE},
+        storage::memory::MemoryStorage,
+    };
+
+    #[tokio::test]
+    async fn smtps_listener_greets_after_handshake() {
+        // 証明書ファイルを使わずにテスト用CAで署名した証明書を生成する
+        let mut config = Config::default();
+        config.set("tls-cert", "").unwrap();
+        config.set("tls-key", "").unwrap();
+        config.set("tls-self-signed", "true").unwrap();
+        let tls = Arc::new(ReloadableTls::new(config.get_tls().clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (ws_tx, _) = broadcast::channel(1);
+        let context = SessionContext {
+            email_store: EmailStore(Arc::new(MemoryStorage::default())),
+            ws_tx,
+            tls: tls.clone(),
+            users: None,
+            token_validator: None,
+            auth_policy: AuthPolicy::default(),
+            max_message_size: MAX_MESSAGE_SIZE,
+        };
+        tokio::spawn(run_smtps_listener(listener, context));
+
+        // 生成したテスト用CAを信頼する
+        let mut roots = RootCertStore::empty();
+        let trust_pem = tls.trust_pem().unwrap();
+        for cert in CertificateDer::pem_slice_iter(trust_pem.as_bytes()) {
+            roots.add(cert.unwrap()).unwrap();
         }
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let tls_socket = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), socket)
+            .await
+            .unwrap();
+
+        // 平文のやり取りを挟まずに、TLS上で挨拶が届く
+        let mut reader = BufReader::new(tls_socket);
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line.as_bytes(), GREETING_MESSAGE_BYTES);
+
+        reader
+            .get_mut()
+            .write_all(b"EHLO client\r\n")
+            .await
+            .unwrap();
+        line.clear();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "250-MyRustSMTP (TLS)\r\n");
     }
 }
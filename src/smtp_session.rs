@@ -0,0 +1,1189 @@
+use std::{io::ErrorKind, sync::Arc};
+
+use anyhow::Result;
+use chrono::Local;
+use log::{error, info, warn};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::broadcast::Sender,
+};
+
+use crate::{
+    auth::{
+        self, cram_md5,
+        oauth::{OAuthMechanism, TokenValidator},
+        scram::ScramSha256,
+        user_db::UserDatabase,
+        Auth, AuthPolicy,
+    },
+    command::{self, Command, WebSocketCommand},
+    config::tls::{ClientCertificate, ReloadableTls},
+    constants::*,
+    email::EmailData,
+    envelope::{Envelope, SmtpPath},
+    util::base64,
+    EmailStore,
+};
+
+/// DATAを読み込む際の1回あたりの最大バイト数
+const DATA_CHUNK_LIMIT: u64 = 64 * 1024;
+
+/// MAIL FROMで受け付けるESMTPパラメータ(AUTHはRFC 4954、値は使わない)
+const MAIL_FROM_PARAMS: [&str; 4] = ["SIZE", "BODY", EXT_SMTPUTF8, "AUTH"];
+/// RCPT TOで受け付けるESMTPパラメータ(DSNには対応していない)
+const RCPT_TO_PARAMS: [&str; 0] = [];
+
+/// SMTPセッション間で共有する設定・ストア
+#[derive(Clone)]
+pub struct SessionContext {
+    pub email_store: EmailStore,
+    pub ws_tx: Sender<String>,
+    /// STARTTLS / SMTPSで使う証明書(再読み込みで差し替わる)
+    pub tls: Arc<ReloadableTls>,
+    /// AUTHの検証に使うユーザーデータベース(Noneならどの資格情報でも認証成功)
+    pub users: Option<Arc<UserDatabase>>,
+    /// XOAUTH2 / OAUTHBEARERのトークンの検証(NoneならOAuthのメカニズムは使えない)
+    pub token_validator: Option<Arc<dyn TokenValidator>>,
+    /// 認証・TLSを必須にするかどうか
+    pub auth_policy: AuthPolicy,
+    /// 受け付けるメッセージサイズの上限(バイト)
+    pub max_message_size: usize,
+}
+
+/// SMTPセッションの状態
+///
+/// 挨拶 → EHLO/HELO → MAIL FROM → RCPT TO → DATA の順に遷移する
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SessionState {
+    /// 挨拶(220)を送信済み、EHLO/HELO待ち
+    Connected,
+    /// EHLO/HELO済み、トランザクション開始待ち
+    Greeted,
+    /// MAIL FROM受付済み
+    MailFrom,
+    /// RCPT TOを1件以上受付済み
+    RcptTo,
+    /// BDATでチャンクを受信中(LAST待ち)
+    Bdat,
+}
+
+/// セッションの終了理由
+pub enum SessionEnd<S> {
+    /// QUITまたは切断
+    Closed,
+    /// STARTTLSを受け付けたので、元のストリームをTLSに切り替える
+    StartTls(S),
+}
+
+/// 1つのSMTP接続を処理するセッション
+///
+/// 平文・TLSどちらのストリームでも同じ状態遷移で処理する
+pub struct SmtpSession<S> {
+    stream: BufReader<S>,
+    // 送信待ちの応答(パイプライン中のコマンドの応答はまとめて送る)
+    replies: Vec<u8>,
+    context: SessionContext,
+    state: SessionState,
+    // 認証状態を保持する
+    auth: Auth,
+    // 現在のトランザクションのエンベロープ
+    envelope: Envelope,
+    // BDATで受信済みのチャンク
+    chunks: Vec<u8>,
+    is_tls: bool,
+    // TLSハンドシェイクで検証したクライアント証明書
+    client_cert: Option<ClientCertificate>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SmtpSession<S> {
+    pub fn new(
+        stream: S,
+        context: SessionContext,
+        is_tls: bool,
+        client_cert: Option<ClientCertificate>,
+    ) -> Self {
+        let mut auth = Auth::default();
+        if let Some(cert) = &client_cert {
+            info!("クライアント証明書 subject:{}", &cert.subject);
+            // 検証済みのクライアント証明書をSMTP AUTHの代わりにする
+            if context.auth_policy.client_cert_auth {
+                auth.complete(cert.username());
+            }
+        }
+
+        Self {
+            stream: BufReader::new(stream),
+            replies: vec![],
+            context,
+            state: SessionState::Connected,
+            auth,
+            envelope: Envelope::default(),
+            chunks: vec![],
+            is_tls,
+            client_cert,
+        }
+    }
+
+    /// クライアントに対して SMTP の挨拶を送信
+    pub async fn greet(&mut self) -> Result<()> {
+        self.send(GREETING_MESSAGE_BYTES).await
+    }
+
+    /// ## Summary
+    /// コマンドを読み込んで接続が終わるまで処理する
+    ///
+    /// ## Returns
+    /// セッションの終了理由(STARTTLSの場合は元のストリームを返す)
+    pub async fn run(mut self) -> Result<SessionEnd<S>> {
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            if self.read_line(&mut line).await? == 0 {
+                // 接続がcloseされた場合
+                break;
+            }
+
+            let command = line.trim_end().to_uppercase();
+            match command::get_command_from_str(&command) {
+                Command::Helo => {
+                    info!("[HELO] command is {}", &command);
+                    self.reset_transaction();
+                    self.state = SessionState::Greeted;
+                    self.send(b"250 Hello\r\n").await?;
+                }
+                Command::Ehlo => {
+                    info!("[EHLO] command is {}", &command);
+                    self.reset_transaction();
+                    self.state = SessionState::Greeted;
+                    let response = self.ehlo_response();
+                    self.send(response.as_bytes()).await?;
+                }
+                Command::StartTls => {
+                    if self.is_tls {
+                        warn!("既にTLS通信です");
+                        self.send(TLS_ALREADY_ACTIVE_MESSAGE_BYTES).await?;
+                    } else if !self.context.tls.is_enabled() {
+                        warn!("STARTTLSをサポートしていません");
+                        self.send(STARTTLS_NO_SUPPORTED_MESSAGE_BYTES).await?;
+                    } else if self.state != SessionState::Greeted {
+                        self.send(BAD_SEQUENCE_MESSAGE_BYTES).await?;
+                    } else {
+                        info!("STARTTLS -> TLSへ切り替え");
+                        self.send(STARTTLS_MESSAGE_BYTES).await?;
+                        self.flush_replies().await?;
+                        // STARTTLS以前にバッファされたデータは破棄する(RFC 3207)
+                        return Ok(SessionEnd::StartTls(self.stream.into_inner()));
+                    }
+                }
+                Command::MailFrom => self.handle_mail_from(&line).await?,
+                Command::RcptTo => self.handle_rcpt_to(&line).await?,
+                Command::Data => self.handle_data().await?,
+                Command::Bdat => self.handle_bdat(&line).await?,
+                Command::Rset => {
+                    self.reset_transaction();
+                    self.send(OK_MESSAGE_BYTES).await?;
+                }
+                Command::Noop => {
+                    self.send(OK_MESSAGE_BYTES).await?;
+                }
+                Command::Quit => {
+                    self.send(b"221 Bye\r\n").await?;
+                    break;
+                }
+                Command::AuthPlain => {
+                    if self.check_auth_sequence().await? {
+                        self.handle_auth_plain(&line).await?;
+                    }
+                }
+                Command::AuthLogin => {
+                    if self.check_auth_sequence().await? {
+                        self.handle_auth_login().await?;
+                    }
+                }
+                Command::AuthCramMd5 => {
+                    if self.check_auth_sequence().await? {
+                        self.handle_auth_cram_md5().await?;
+                    }
+                }
+                Command::AuthScramSha256 => {
+                    if self.check_auth_sequence().await? {
+                        self.handle_auth_scram_sha256(&line).await?;
+                    }
+                }
+                Command::AuthXOAuth2 => {
+                    if self.check_auth_sequence().await? {
+                        self.handle_auth_oauth(&line, AUTH_XOAUTH2, OAuthMechanism::XOAuth2)
+                            .await?;
+                    }
+                }
+                Command::AuthOAuthBearer => {
+                    if self.check_auth_sequence().await? {
+                        self.handle_auth_oauth(
+                            &line,
+                            AUTH_OAUTHBEARER,
+                            OAuthMechanism::OAuthBearer,
+                        )
+                        .await?;
+                    }
+                }
+                Command::Unknown => {
+                    self.send(b"500 Unrecongnized command\r\n").await?;
+                }
+            }
+        }
+
+        self.flush_replies().await?;
+        Ok(SessionEnd::Closed)
+    }
+
+    /// CRAM-MD5が使えるかどうか
+    ///
+    /// 平文のパスワードが必要なので、ユーザーデータベースがないか `{PLAIN}` のユーザーがいる場合のみ
+    fn supports_cram_md5(&self) -> bool {
+        self.context
+            .users
+            .as_deref()
+            .is_none_or(UserDatabase::supports_cram_md5)
+    }
+
+    /// EHLOの応答(対応している拡張の一覧)
+    fn ehlo_response(&self) -> String {
+        let mut extensions = vec![];
+        if !self.is_tls && self.context.tls.is_enabled() {
+            extensions.push("STARTTLS".to_string());
+        }
+        // TLS必須の場合、平文の間はAUTHを通知しない
+        if self.is_tls || !self.context.auth_policy.require_tls {
+            let mut mechanisms = vec!["LOGIN", "PLAIN"];
+            if self.supports_cram_md5() {
+                mechanisms.push("CRAM-MD5");
+            }
+            // SCRAMはサーバー側の検証値が必要なのでユーザーデータベースがある場合のみ
+            if self.context.users.is_some() {
+                mechanisms.push("SCRAM-SHA-256");
+            }
+            if self.context.token_validator.is_some() {
+                mechanisms.push("XOAUTH2");
+                mechanisms.push("OAUTHBEARER");
+            }
+            extensions.push(format!("AUTH {}", mechanisms.join(" ")));
+        }
+        extensions.push(format!("SIZE {}", self.context.max_message_size));
+        extensions.push(EXT_8BITMIME.to_string());
+        extensions.push(EXT_SMTPUTF8.to_string());
+        extensions.push(EXT_PIPELINING.to_string());
+        extensions.push(EXT_CHUNKING.to_string());
+
+        let hostname = if self.is_tls {
+            "MyRustSMTP (TLS)"
+        } else {
+            "MyRustSMTP"
+        };
+        let mut response = format!("250-{}\r\n", hostname);
+        for (i, extension) in extensions.iter().enumerate() {
+            let separator = if i == extensions.len() - 1 { ' ' } else { '-' };
+            response.push_str(&format!("250{}{}\r\n", separator, extension));
+        }
+        response
+    }
+
+    /// トランザクション(エンベロープ)を破棄してEHLO直後の状態に戻す
+    fn reset_transaction(&mut self) {
+        self.envelope = Envelope::default();
+        self.chunks = vec![];
+        if self.state != SessionState::Connected {
+            self.state = SessionState::Greeted;
+        }
+    }
+
+    async fn handle_mail_from(&mut self, line: &str) -> Result<()> {
+        if self.state != SessionState::Greeted {
+            // EHLO前、またはトランザクション中のMAIL FROM
+            return self.send(BAD_SEQUENCE_MESSAGE_BYTES).await;
+        }
+        if self.context.auth_policy.require_tls && !self.is_tls {
+            return self.send(ENCRYPTION_REQUIRED_MESSAGE_BYTES).await;
+        }
+        if self.context.auth_policy.require_auth && !*self.auth.get_authenticated() {
+            return self.send(AUTH_REQUIRED_MESSAGE_BYTES).await;
+        }
+
+        match SmtpPath::parse(line, MAILFROM) {
+            Some(path) => {
+                // 未対応のパラメータは555で拒否する(RFC 5321 4.1.1.11)
+                if let Some(param) = path.find_unsupported_param(&MAIL_FROM_PARAMS) {
+                    warn!("未対応のMAIL FROMパラメータ: {}", param);
+                    return self.send(PARAMETERS_NOT_RECOGNIZED_MESSAGE_BYTES).await;
+                }
+                // SIZE=<予定サイズ> が上限を超えていれば受け付けない(RFC 1870)
+                let declared_size = path
+                    .get_param("SIZE")
+                    .and_then(|size| size.as_ref())
+                    .and_then(|size| size.parse::<usize>().ok());
+                if declared_size.is_some_and(|size| size > self.context.max_message_size) {
+                    return self.send(MESSAGE_SIZE_EXCEEDED_MESSAGE_BYTES).await;
+                }
+
+                // BODY=7BIT / BODY=8BITMIME (RFC 6152)
+                let body_type = path.get_param("BODY").cloned();
+                let is_8bitmime = match body_type {
+                    None => false,
+                    Some(Some(body)) if body.eq_ignore_ascii_case("7BIT") => false,
+                    Some(Some(body)) if body.eq_ignore_ascii_case(EXT_8BITMIME) => true,
+                    Some(_) => {
+                        return self.send(PARAMETERS_NOT_RECOGNIZED_MESSAGE_BYTES).await;
+                    }
+                };
+                // SMTPUTF8 (RFC 6531)
+                let is_smtputf8 = path.get_param(EXT_SMTPUTF8).is_some();
+
+                self.envelope.begin(path);
+                if is_8bitmime {
+                    self.envelope.add_extension(EXT_8BITMIME);
+                }
+                if is_smtputf8 {
+                    self.envelope.add_extension(EXT_SMTPUTF8);
+                }
+                self.record_pipelining();
+                self.state = SessionState::MailFrom;
+                self.send(OK_MESSAGE_BYTES).await
+            }
+            None => self.send(SYNTAX_ERROR_MESSAGE_BYTES).await,
+        }
+    }
+
+    /// 応答を待たずに次のコマンドが届いていればPIPELININGが使われたとみなす
+    fn record_pipelining(&mut self) {
+        if !self.stream.buffer().is_empty() {
+            self.envelope.add_extension(EXT_PIPELINING);
+        }
+    }
+
+    async fn handle_rcpt_to(&mut self, line: &str) -> Result<()> {
+        if self.state != SessionState::MailFrom && self.state != SessionState::RcptTo {
+            return self.send(BAD_SEQUENCE_MESSAGE_BYTES).await;
+        }
+
+        match SmtpPath::parse(line, RCPTTO) {
+            Some(path) => {
+                if let Some(param) = path.find_unsupported_param(&RCPT_TO_PARAMS) {
+                    warn!("未対応のRCPT TOパラメータ: {}", param);
+                    return self.send(PARAMETERS_NOT_RECOGNIZED_MESSAGE_BYTES).await;
+                }
+                self.envelope.add_rcpt_to(path);
+                self.record_pipelining();
+                self.state = SessionState::RcptTo;
+                self.send(OK_MESSAGE_BYTES).await
+            }
+            None => self.send(SYNTAX_ERROR_MESSAGE_BYTES).await,
+        }
+    }
+
+    async fn handle_data(&mut self) -> Result<()> {
+        if self.state != SessionState::RcptTo {
+            return self.send(BAD_SEQUENCE_MESSAGE_BYTES).await;
+        }
+
+        self.send(b"354 End data with <CR><LF>.<CR><LF>\r\n").await?;
+
+        let Some(email_content) = self.read_data().await? else {
+            // 終端(.)を受け取る前に切断された場合はトランザクションを破棄する
+            self.reset_transaction();
+            return Ok(());
+        };
+        if email_content.len() > self.context.max_message_size {
+            self.reset_transaction();
+            return self.send(MESSAGE_SIZE_EXCEEDED_MESSAGE_BYTES).await;
+        }
+
+        self.deliver(email_content).await
+    }
+
+    /// ## Summary
+    /// `BDAT <size> [LAST]` を処理する(RFC 3030)
+    ///
+    /// ## Note
+    /// チャンクは指定されたバイト数をそのまま読み込み、LASTを受け取った時点で1通のメールにまとめる
+    async fn handle_bdat(&mut self, line: &str) -> Result<()> {
+        let mut args = line.split_whitespace().skip(1);
+        let size = args.next().and_then(|size| size.parse::<u64>().ok());
+        let last = match args.next() {
+            None => false,
+            Some(arg) if arg.eq_ignore_ascii_case("LAST") => true,
+            Some(_) => return self.send(SYNTAX_ERROR_MESSAGE_BYTES).await,
+        };
+        let Some(size) = size else {
+            return self.send(SYNTAX_ERROR_MESSAGE_BYTES).await;
+        };
+
+        // チャンク本体は応答に関わらず送られてくるので、エラー時も読み捨てる
+        if self.state != SessionState::RcptTo && self.state != SessionState::Bdat {
+            self.discard_bytes(size).await?;
+            return self.send(BAD_SEQUENCE_MESSAGE_BYTES).await;
+        }
+        // サイズはクライアントが自由に指定できるので、溢れる場合も上限超過として扱う
+        let total_size = (self.chunks.len() as u64).checked_add(size);
+        let max_size = self.context.max_message_size as u64;
+        if total_size.is_none_or(|total_size| total_size > max_size) {
+            self.discard_bytes(size).await?;
+            self.reset_transaction();
+            return self.send(MESSAGE_SIZE_EXCEEDED_MESSAGE_BYTES).await;
+        }
+
+        if (self.stream.buffer().len() as u64) < size {
+            self.flush_replies().await?;
+        }
+        // 指定サイズ分を先に確保せず、実際に受信した分だけ追加していく
+        let received = (&mut self.stream)
+            .take(size)
+            .read_to_end(&mut self.chunks)
+            .await?;
+        if (received as u64) < size {
+            // チャンクの途中で切断された場合はトランザクションを破棄する
+            self.reset_transaction();
+            return Ok(());
+        }
+
+        self.envelope.add_extension(EXT_CHUNKING);
+        if !last {
+            self.state = SessionState::Bdat;
+            let message = format!("250 Ok {} octets received\r\n", size);
+            return self.send(message.as_bytes()).await;
+        }
+
+        let email_content = std::mem::take(&mut self.chunks);
+        self.deliver(email_content).await
+    }
+
+    /// 指定したバイト数を読み捨てる
+    async fn discard_bytes(&mut self, size: u64) -> Result<()> {
+        tokio::io::copy(&mut (&mut self.stream).take(size), &mut tokio::io::sink()).await?;
+        Ok(())
+    }
+
+    /// 受信したメールを保存してトランザクションを終える
+    async fn deliver(&mut self, email_content: Vec<u8>) -> Result<()> {
+        let mut envelope = std::mem::take(&mut self.envelope);
+        if *self.auth.get_authenticated() {
+            envelope.set_auth_user(Some(self.auth.get_username().clone()));
+        }
+        if let Some(cert) = &self.client_cert {
+            envelope.set_client_cert_subject(Some(cert.subject.clone()));
+        }
+        self.state = SessionState::Greeted;
+        let mail_data = EmailData::new(email_content, envelope, Local::now());
+
+        // 受信したメールを共有ストアに保存
+        if let Err(e) = self.context.email_store.0.save(mail_data).await {
+            error!("メールの保存に失敗しました: {}", e);
+            return self.send(LOCAL_ERROR_MESSAGE_BYTES).await;
+        }
+        // WebSocket 用に新着メール通知を送信
+        let _ = self.context.ws_tx.send(WebSocketCommand::Update.into());
+
+        self.send(b"250 Ok:queued\r\n").await
+    }
+
+    /// ## Summary
+    /// DATAの本文を終端(`<CRLF>.<CRLF>`)まで読み込む
+    ///
+    /// ## Note
+    /// 本文はバイト列のまま扱い、行頭の "." は1つ取り除く(dot-unstuffing, RFC 5321 4.5.2)
+    /// 上限サイズを超えた分は読み捨てる(上限 + 1 バイトまで保持するので呼び出し側で判定できる)
+    ///
+    /// ## Returns
+    /// 本文のバイト列(終端前に切断された場合はNone)
+    async fn read_data(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut data = vec![];
+        let mut chunk = vec![];
+        // 長すぎる行は分割して読むので、行頭かどうかを別途管理する
+        let mut at_line_start = true;
+
+        loop {
+            self.flush_before_read().await?;
+            chunk.clear();
+            let bytes_read = match (&mut self.stream)
+                .take(DATA_CHUNK_LIMIT)
+                .read_until(b'\n', &mut chunk)
+                .await
+            {
+                Ok(bytes_read) => bytes_read,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    warn!("{}", e);
+                    0
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if bytes_read == 0 {
+                // クライアントが切断
+                return Ok(None);
+            }
+
+            let mut line = chunk.as_slice();
+            if at_line_start {
+                if line == b".\r\n" || line == b".\n" {
+                    // 行にドットのみならデータ終了
+                    return Ok(Some(data));
+                }
+                if line.starts_with(b".") {
+                    line = &line[1..];
+                }
+            }
+            at_line_start = chunk.ends_with(b"\n");
+
+            if data.len() <= self.context.max_message_size {
+                let remaining = self.context.max_message_size + 1 - data.len();
+                data.extend_from_slice(&line[..line.len().min(remaining)]);
+            }
+        }
+    }
+
+    /// AUTHを受け付けられる状態か確認する(受け付けられない場合は503を返す)
+    async fn check_auth_sequence(&mut self) -> Result<bool> {
+        if self.state != SessionState::Greeted || *self.auth.get_authenticated() {
+            self.send(BAD_SEQUENCE_MESSAGE_BYTES).await?;
+            return Ok(false);
+        }
+        if self.context.auth_policy.require_tls && !self.is_tls {
+            self.send(ENCRYPTION_REQUIRED_MESSAGE_BYTES).await?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    async fn handle_auth_plain(&mut self, line: &str) -> Result<()> {
+        // AUTH PLAIN AHVzZXIAcGFzc3dvcmQ=   <-- 「\0user\0password」を base64 エンコードした文字列
+        let Some(credentials) = self.read_initial_response(line, AUTH_PLAIN).await? else {
+            return Ok(());
+        };
+        let message_bytes = self
+            .auth
+            .plain(credentials.as_bytes(), self.context.users.as_deref());
+        self.send(message_bytes).await
+    }
+
+    async fn handle_auth_login(&mut self) -> Result<()> {
+        let mut line = String::new();
+        // Username base64
+        self.send(b"334 VXNlcm5hbWU6\r\n").await?;
+        self.read_line(&mut line).await?;
+        if line.trim() == "*" {
+            return self.send(AUTH_CANCELED_MESSAGE_BYTES).await;
+        }
+        let username = match base64::decode(&line) {
+            Ok(usr) => usr,
+            Err(e) => {
+                error!("{}", e);
+                return self.send(INVALID_BASE64_MESSAGE_BYTES).await;
+            }
+        };
+
+        line.clear();
+        self.send(b"334 UGFzc3dvcmQ6\r\n").await?;
+        self.read_line(&mut line).await?;
+        if line.trim() == "*" {
+            return self.send(AUTH_CANCELED_MESSAGE_BYTES).await;
+        }
+        let password = match base64::decode(&line) {
+            Ok(usr) => usr,
+            Err(e) => {
+                error!("{}", e);
+                return self.send(INVALID_BASE64_MESSAGE_BYTES).await;
+            }
+        };
+
+        let message_bytes = self
+            .auth
+            .login(username, &password, self.context.users.as_deref());
+        self.send(message_bytes).await
+    }
+
+    async fn handle_auth_cram_md5(&mut self) -> Result<()> {
+        if !self.supports_cram_md5() {
+            warn!("{{PLAIN}}のユーザーがいないためCRAM-MD5は使えません");
+            return self.send(AUTH_MECHANISM_UNSUPPORTED_MESSAGE_BYTES).await;
+        }
+
+        let challenge = cram_md5::challenge();
+        let message = format!("334 {}\r\n", base64::encode(challenge.as_bytes()));
+        let Some(response) = self.read_sasl_response(message.as_bytes()).await? else {
+            return Ok(());
+        };
+        let message_bytes =
+            self.auth
+                .cram_md5(&challenge, &response, self.context.users.as_deref());
+        self.send(message_bytes).await
+    }
+
+    async fn handle_auth_scram_sha256(&mut self, line: &str) -> Result<()> {
+        let Some(users) = self.context.users.clone() else {
+            warn!("ユーザーデータベースがないためSCRAM-SHA-256は使えません");
+            return self.send(AUTH_MECHANISM_UNSUPPORTED_MESSAGE_BYTES).await;
+        };
+
+        let Some(client_first) = self.read_initial_response(line, AUTH_SCRAM_SHA256).await? else {
+            return Ok(());
+        };
+
+        let (scram, server_first) = match ScramSha256::start(&client_first, &users) {
+            Ok(started) => started,
+            Err(e) => {
+                warn!("SCRAM-SHA-256 認証失敗: {}", e);
+                return self.send(auth::FAILED_MESSAGE_BYTES).await;
+            }
+        };
+        let challenge = format!("334 {}\r\n", base64::encode(server_first.as_bytes()));
+        let Some(client_final) = self.read_sasl_response(challenge.as_bytes()).await? else {
+            return Ok(());
+        };
+        let server_final = match scram.finish(&client_final) {
+            Ok(server_final) => server_final,
+            Err(e) => {
+                warn!(
+                    "SCRAM-SHA-256 認証失敗 username:{} {}",
+                    scram.get_username(),
+                    e
+                );
+                return self.send(auth::FAILED_MESSAGE_BYTES).await;
+            }
+        };
+
+        // ServerSignatureを送り、クライアントの空の応答を待ってから成功を返す
+        let challenge = format!("334 {}\r\n", base64::encode(server_final.as_bytes()));
+        if self
+            .read_sasl_response(challenge.as_bytes())
+            .await?
+            .is_none()
+        {
+            return Ok(());
+        }
+        let message_bytes = self.auth.complete(scram.get_username().to_string());
+        self.send(message_bytes).await
+    }
+
+    async fn handle_auth_oauth(
+        &mut self,
+        line: &str,
+        command: &str,
+        mechanism: OAuthMechanism,
+    ) -> Result<()> {
+        let Some(validator) = self.context.token_validator.clone() else {
+            warn!(
+                "トークンの検証が設定されていないため{}は使えません",
+                command
+            );
+            return self.send(AUTH_MECHANISM_UNSUPPORTED_MESSAGE_BYTES).await;
+        };
+
+        let Some(response) = self.read_initial_response(line, command).await? else {
+            return Ok(());
+        };
+
+        let validated = mechanism.parse(&response).and_then(|credentials| {
+            validator.validate(credentials.username.as_deref(), &credentials.token)
+        });
+        match validated {
+            Ok(username) => {
+                let message_bytes = self.auth.complete(username);
+                self.send(message_bytes).await
+            }
+            Err(e) => {
+                warn!("{} 認証失敗: {}", command, e);
+                // エラー内容(JSON)を送り、クライアントの応答を待ってから535を返す
+                let challenge = format!(
+                    "334 {}\r\n",
+                    base64::encode(mechanism.error_challenge().as_bytes())
+                );
+                self.send(challenge.as_bytes()).await?;
+                let mut line = String::new();
+                self.read_line(&mut line).await?;
+                self.send(auth::FAILED_MESSAGE_BYTES).await
+            }
+        }
+    }
+
+    /// ## Summary
+    /// AUTHコマンドの初期応答(RFC 4954)を受け取る
+    ///
+    /// ## Parameters
+    /// - `line`: AUTHコマンドの行
+    /// - `command`: `AUTH PLAIN` 等、初期応答の前までのコマンド
+    ///
+    /// ## Returns
+    /// デコードした初期応答(なければ空のチャレンジを送って受け取る)
+    /// キャンセルまたは不正な場合はエラー応答を送ってNone
+    async fn read_initial_response(&mut self, line: &str, command: &str) -> Result<Option<String>> {
+        let initial = line.trim_end().get(command.len()..).unwrap_or("");
+        if initial.trim().is_empty() {
+            return self.read_sasl_response(b"334 \r\n").await;
+        }
+        match base64::decode(initial) {
+            Ok(response) => Ok(Some(response)),
+            Err(e) => {
+                error!("{}", e);
+                self.send(INVALID_BASE64_MESSAGE_BYTES).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// ## Summary
+    /// チャレンジを送ってクライアントのbase64の応答を受け取る
+    ///
+    /// ## Returns
+    /// デコードした応答(キャンセルまたは不正な場合はエラー応答を送ってNone)
+    async fn read_sasl_response(&mut self, challenge: &[u8]) -> Result<Option<String>> {
+        self.send(challenge).await?;
+        let mut line = String::new();
+        self.read_line(&mut line).await?;
+        if line.trim() == "*" {
+            self.send(AUTH_CANCELED_MESSAGE_BYTES).await?;
+            return Ok(None);
+        }
+        match base64::decode(&line) {
+            Ok(response) => Ok(Some(response)),
+            Err(e) => {
+                error!("{}", e);
+                self.send(INVALID_BASE64_MESSAGE_BYTES).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// ## Summary
+    /// クライアントにメッセージを送る
+    ///
+    /// ## Note
+    /// パイプラインで次のコマンドが既に届いている間は応答をためておき、
+    /// 読み込み待ちになる前にまとめて送信する(RFC 2920)
+    ///
+    /// ## Parameters
+    /// - `msg_byte`: 送信するメッセージ
+    async fn send(&mut self, msg_byte: &[u8]) -> Result<()> {
+        self.replies.extend_from_slice(msg_byte);
+        if self.stream.buffer().is_empty() {
+            self.flush_replies().await?;
+        }
+        Ok(())
+    }
+
+    /// ## Summary
+    /// ためている応答を送信する
+    ///
+    /// ## Note
+    /// ErrorKind::UnexpectedEofはrustlsだとERROR扱いになる
+    /// 相手が close_notify を送らずに接続を閉じた時に起きるerror
+    /// 一応このメールサーバーではclose_notifyがなくても正常終了とみなします。
+    async fn flush_replies(&mut self) -> Result<()> {
+        if self.replies.is_empty() {
+            return Ok(());
+        }
+        let replies = std::mem::take(&mut self.replies);
+        let writer = self.stream.get_mut();
+        if let Err(e) = writer.write_all(&replies).await {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                // TLS的には “close_notify” が来ていないが、
+                // こちらとしては「相手が接続を閉じただけ」として扱う。
+                warn!("{}", e);
+                return Ok(());
+            }
+            return Err(e.into());
+        }
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// 受信済みのデータに次の1行が揃っていなければ、読み込み待ちになる前に応答を送る
+    async fn flush_before_read(&mut self) -> Result<()> {
+        if !self.stream.buffer().contains(&b'\n') {
+            self.flush_replies().await?;
+        }
+        Ok(())
+    }
+
+    /// 1行読み込む(切断された場合は0を返す)
+    ///
+    /// UTF-8として不正なバイトが含まれていても切断せずに置換文字として扱う
+    async fn read_line(&mut self, line: &mut String) -> Result<usize> {
+        self.flush_before_read().await?;
+        let mut bytes = vec![];
+        match self.stream.read_until(b'\n', &mut bytes).await {
+            Ok(result) => {
+                line.push_str(&String::from_utf8_lossy(&bytes));
+                Ok(result)
+            }
+            Err(e) => {
+                if e.kind() == ErrorKind::UnexpectedEof {
+                    // TLS的には “close_notify” が来ていないが、
+                    // こちらとしては「相手が接続を閉じただけ」として扱う。
+                    warn!("{}", e);
+                    return Ok(0);
+                }
+                Err(e.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{config::Config, storage::memory::MemoryStorage};
+
+    fn context(max_message_size: usize) -> SessionContext {
+        // 証明書ファイルなし(平文のみ)
+        let mut config = Config::default();
+        config.set("tls-cert", "").unwrap();
+        config.set("tls-key", "").unwrap();
+        SessionContext {
+            email_store: EmailStore(Arc::new(MemoryStorage::default())),
+            ws_tx: tokio::sync::broadcast::channel(1).0,
+            tls: Arc::new(ReloadableTls::new(config.get_tls().clone())),
+            users: None,
+            token_validator: None,
+            auth_policy: AuthPolicy::default(),
+            max_message_size,
+        }
+    }
+
+    /// クライアントとして `input` を送り、切断するまでの応答を返す
+    async fn converse(context: SessionContext, input: &[u8]) -> String {
+        converse_with_cert(context, None, input).await
+    }
+
+    /// クライアント証明書を提示した接続として `input` を送り、応答を返す
+    async fn converse_with_cert(
+        context: SessionContext,
+        client_cert: Option<ClientCertificate>,
+        input: &[u8],
+    ) -> String {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let session = tokio::spawn(async move {
+            let mut session = SmtpSession::new(server, context, false, client_cert);
+            session.greet().await?;
+            session.run().await.map(|_| ())
+        });
+        client.write_all(input).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        session.await.unwrap().unwrap();
+        output
+    }
+
+    const TRANSACTION: &str =
+        "EHLO client\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\n";
+
+    #[tokio::test]
+    async fn delivers_message_with_envelope() {
+        let context = context(1024);
+        let store = context.email_store.clone();
+        let input = format!(
+            "{}DATA\r\nSubject: a\r\n\r\nbody\r\n.\r\nQUIT\r\n",
+            TRANSACTION
+        );
+        let output = converse(context, input.as_bytes()).await;
+        assert!(output.starts_with("220 "), "{}", output);
+        // TLSの設定がなければSTARTTLSは広告しない
+        assert!(!output.contains("STARTTLS"), "{}", output);
+        assert!(output.contains("250 Ok:queued\r\n"), "{}", output);
+        assert!(output.ends_with("221 Bye\r\n"), "{}", output);
+
+        let emails = store.0.list().await.unwrap();
+        assert_eq!(emails.len(), 1);
+        assert_eq!(
+            emails[0].get_raw().as_slice(),
+            b"Subject: a\r\n\r\nbody\r\n"
+        );
+        assert_eq!(
+            emails[0].get_envelope().get_sender().as_deref(),
+            Some("a@example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_commands_out_of_sequence() {
+        let output = converse(
+            context(1024),
+            b"MAIL FROM:<a@example.com>\r\nEHLO client\r\nRCPT TO:<b@example.com>\r\nDATA\r\n",
+        )
+        .await;
+        let replies: Vec<&str> = output.lines().collect();
+        assert!(replies[1].starts_with("503 "), "{}", output);
+        assert!(replies[replies.len() - 2].starts_with("503 "), "{}", output);
+        assert!(replies[replies.len() - 1].starts_with("503 "), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn data_removes_dot_stuffing() {
+        let context = context(1024);
+        let store = context.email_store.clone();
+        let input = format!("{}DATA\r\nSubject: a\r\n\r\n..line\r\n.\r\n", TRANSACTION);
+        let output = converse(context, input.as_bytes()).await;
+        assert!(output.contains("250 Ok:queued"), "{}", output);
+
+        let emails = store.0.list().await.unwrap();
+        assert_eq!(emails.len(), 1);
+        assert_eq!(
+            emails[0].get_raw().as_slice(),
+            b"Subject: a\r\n\r\n.line\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn data_rejects_message_over_limit() {
+        let context = context(10);
+        let store = context.email_store.clone();
+        let input = format!("{}DATA\r\n0123456789\r\n.\r\nQUIT\r\n", TRANSACTION);
+        let output = converse(context, input.as_bytes()).await;
+        assert!(output.contains("552 "), "{}", output);
+        assert!(output.ends_with("221 Bye\r\n"), "{}", output);
+        assert!(store.0.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn mail_from_rejects_declared_size_over_limit() {
+        let output = converse(
+            context(10),
+            b"EHLO client\r\nMAIL FROM:<a@example.com> SIZE=11\r\nMAIL FROM:<a@example.com> SIZE=10\r\n",
+        )
+        .await;
+        assert!(output.contains("250-SIZE 10\r\n"), "{}", output);
+        let replies: Vec<&str> = output.lines().rev().take(2).collect();
+        assert!(replies[1].starts_with("552 "), "{}", output);
+        assert!(replies[0].starts_with("250 "), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn records_esmtp_extensions() {
+        let context = context(1024);
+        let store = context.email_store.clone();
+        // 応答を待たずにコマンドをまとめて送る(PIPELINING)
+        let output = converse(
+            context,
+            b"EHLO client\r\nMAIL FROM:<a@example.com> BODY=8BITMIME SMTPUTF8\r\n\
+              RCPT TO:<b@example.com>\r\nDATA\r\nSubject: \xc3\xa9\r\n\r\nbody\r\n.\r\n",
+        )
+        .await;
+        for extension in ["8BITMIME", "SMTPUTF8", "PIPELINING"] {
+            assert!(output.contains(extension), "{}", output);
+        }
+        assert!(output.contains("250 Ok:queued"), "{}", output);
+
+        let emails = store.0.list().await.unwrap();
+        assert_eq!(
+            emails[0].get_envelope().get_extensions(),
+            &["8BITMIME", "SMTPUTF8", "PIPELINING"]
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_from_rejects_unknown_body_type() {
+        let output = converse(
+            context(1024),
+            b"EHLO client\r\nMAIL FROM:<a@example.com> BODY=BINARYMIME\r\n",
+        )
+        .await;
+        let reply = output.lines().last().unwrap();
+        assert!(reply.starts_with("555 "), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn unsupported_parameters_answer_555() {
+        let output = converse(
+            context(1024),
+            b"EHLO client\r\n\
+              MAIL FROM:<a@example.com> BODY=BINARYMIME\r\n\
+              MAIL FROM:<a@example.com> RET=HDRS\r\n\
+              MAIL FROM:<a@example.com> SIZE=10 BODY=8BITMIME SMTPUTF8 AUTH=<>\r\n\
+              RCPT TO:<b@example.com> NOTIFY=NEVER\r\n\
+              RCPT TO:<b@example.com>\r\n\
+              QUIT\r\n",
+        )
+        .await;
+        let replies: Vec<&str> = output.lines().rev().take(6).collect();
+        let codes: Vec<&str> = replies.iter().rev().map(|reply| &reply[..3]).collect();
+        assert_eq!(
+            codes,
+            ["555", "555", "250", "555", "250", "221"],
+            "{}",
+            output
+        );
+    }
+
+    #[tokio::test]
+    async fn bdat_delivers_chunks() {
+        let context = context(1024);
+        let store = context.email_store.clone();
+        let input = format!(
+            "{}BDAT 5\r\nHi: aBDAT 5 LAST\r\n\r\nb\r\nQUIT\r\n",
+            TRANSACTION
+        );
+        let output = converse(context, input.as_bytes()).await;
+        assert!(output.contains("250 Ok 5 octets received"), "{}", output);
+        assert!(output.contains("250 Ok:queued"), "{}", output);
+
+        let emails = store.0.list().await.unwrap();
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].get_raw().as_slice(), b"Hi: a\r\nb\r\n");
+    }
+
+    #[tokio::test]
+    async fn bdat_rejects_size_over_limit() {
+        let input = format!("{}BDAT 11 LAST\r\n01234567890QUIT\r\n", TRANSACTION);
+        let output = converse(context(10), input.as_bytes()).await;
+        assert!(output.contains("552 "), "{}", output);
+        assert!(output.ends_with("221 Bye\r\n"), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn bdat_rejects_overflowing_size() {
+        // 受信済みのチャンクのサイズとの合計がu64を超えてもpanicせず552を返す
+        let input = format!("{}BDAT 2\r\nabBDAT {} LAST\r\n", TRANSACTION, u64::MAX);
+        let output = converse(context(1024), input.as_bytes()).await;
+        assert!(output.contains("250 Ok 2 octets received"), "{}", output);
+        assert!(
+            output
+                .trim_end()
+                .ends_with("552 Message size exceeds fixed maximum message size"),
+            "{}",
+            output
+        );
+    }
+
+    #[tokio::test]
+    async fn require_auth_rejects_mail_from_until_authenticated() {
+        let mut context = context(1024);
+        context.auth_policy.require_auth = true;
+        let output = converse(
+            context,
+            b"EHLO client\r\nMAIL FROM:<a@example.com>\r\nAUTH PLAIN AGFsaWNlAHNlY3JldA==\r\n\
+              MAIL FROM:<a@example.com>\r\n",
+        )
+        .await;
+        let replies: Vec<&str> = output.lines().rev().take(3).collect();
+        assert!(replies[2].starts_with("530 "), "{}", output);
+        assert!(replies[1].starts_with("235 "), "{}", output);
+        assert!(replies[0].starts_with("250 "), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn auth_plain_reads_initial_response_after_challenge() {
+        let output = converse(
+            context(1024),
+            b"EHLO client\r\nAUTH PLAIN\r\nAGFsaWNlAHNlY3JldA==\r\n",
+        )
+        .await;
+        let replies: Vec<&str> = output.lines().rev().take(2).collect();
+        assert_eq!(replies[1], "334 ", "{}", output);
+        assert!(replies[0].starts_with("235 "), "{}", output);
+
+        let output = converse(context(1024), b"EHLO client\r\nAUTH PLAIN !!!\r\n").await;
+        assert!(
+            output.lines().last().unwrap().starts_with("501 "),
+            "{}",
+            output
+        );
+    }
+
+    #[tokio::test]
+    async fn cram_md5_requires_plain_users() {
+        let mut context = context(1024);
+        let scram_only = format!("carol:{}\n", auth::user_db::hash_scram_password("pw"));
+        context.users = Some(Arc::new(UserDatabase::parse(&scram_only).unwrap()));
+        let output = converse(context, b"EHLO client\r\nAUTH CRAM-MD5\r\n").await;
+        assert!(
+            output.contains("250-AUTH LOGIN PLAIN SCRAM-SHA-256\r\n"),
+            "{}",
+            output
+        );
+        let reply = output.lines().last().unwrap();
+        assert_eq!(reply, "504 Unrecognized authentication type", "{}", output);
+
+        // {PLAIN}のユーザーがいれば通知する
+        let mut context = self::context(1024);
+        context.users = Some(Arc::new(UserDatabase::parse("alice:{PLAIN}pw").unwrap()));
+        let output = converse(context, b"EHLO client\r\n").await;
+        assert!(
+            output.contains("250-AUTH LOGIN PLAIN CRAM-MD5 SCRAM-SHA-256\r\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[tokio::test]
+    async fn require_tls_answers_538_for_mail_and_auth() {
+        let mut context = context(1024);
+        context.auth_policy.require_tls = true;
+        let output = converse(
+            context,
+            b"EHLO client\r\nAUTH PLAIN AGFsaWNlAHB3\r\nMAIL FROM:<a@example.com>\r\nQUIT\r\n",
+        )
+        .await;
+        let replies: Vec<&str> = output.lines().collect();
+        let encryption_required = "538 Encryption required, issue a STARTTLS command first";
+        // AUTH・MAIL FROMの応答(最後はQUITの応答)
+        let rejected = &replies[replies.len() - 3..replies.len() - 1];
+        assert_eq!(rejected, [encryption_required; 2], "{}", output);
+        // 平文の間はAUTHを通知しない
+        assert!(!output.contains("AUTH "), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn records_authenticated_user() {
+        let context = context(1024);
+        let store = context.email_store.clone();
+        // 認証した後のトランザクションはすべて認証ユーザーの送信として記録する
+        let mail = "MAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDATA\r\n\r\n.\r\n";
+        let input = format!(
+            "EHLO client\r\nAUTH PLAIN AGFsaWNlAHNlY3JldA==\r\n{}{}",
+            mail, mail
+        );
+        let output = converse(context, input.as_bytes()).await;
+        assert_eq!(output.matches("250 Ok:queued").count(), 2, "{}", output);
+
+        let emails = store.0.list().await.unwrap();
+        assert_eq!(emails.len(), 2);
+        assert!(emails.iter().all(|email| email.is_sent_by("alice")));
+    }
+
+    #[tokio::test]
+    async fn client_certificate_authenticates_session() {
+        let mut context = context(1024);
+        context.auth_policy.require_auth = true;
+        context.auth_policy.client_cert_auth = true;
+        let store = context.email_store.clone();
+        let cert = ClientCertificate {
+            subject: "CN=relay1, O=Example".to_string(),
+            common_name: Some("relay1".to_string()),
+        };
+        let input = format!("{}DATA\r\n\r\n.\r\n", TRANSACTION);
+        let output = converse_with_cert(context, Some(cert), input.as_bytes()).await;
+        assert!(output.contains("250 Ok:queued"), "{}", output);
+
+        let emails = store.0.list().await.unwrap();
+        let envelope = emails[0].get_envelope();
+        assert!(emails[0].is_sent_by("relay1"));
+        assert_eq!(
+            envelope.get_client_cert_subject().as_deref(),
+            Some("CN=relay1, O=Example")
+        );
+    }
+
+    #[tokio::test]
+    async fn client_certificate_is_only_recorded_without_client_cert_auth() {
+        let mut context = context(1024);
+        context.auth_policy.require_auth = true;
+        let cert = ClientCertificate {
+            subject: "CN=relay1".to_string(),
+            common_name: Some("relay1".to_string()),
+        };
+        let output = converse_with_cert(context, Some(cert), TRANSACTION.as_bytes()).await;
+        assert!(output.contains("530 "), "{}", output);
+    }
+}
@@ -0,0 +1,121 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::email::EmailData;
+
+use super::MailStorage;
+
+/// ## Summary
+/// 1ユーザーのメールボックスだけを見せるストレージ
+///
+/// ## Note
+/// 元のストレージのうち、そのユーザーがSMTP AUTHで送信したメールだけを読み書きできる
+/// 宛先(Toヘッダー等)は送信者が自由に書けるので、他人のメールを読めないよう振り分けには使わない
+/// HTTP APIで認証したユーザーごとに作る
+pub struct MailboxStorage {
+    inner: Arc<dyn MailStorage>,
+    username: String,
+}
+
+impl MailboxStorage {
+    pub fn new(inner: Arc<dyn MailStorage>, username: String) -> Self {
+        Self { inner, username }
+    }
+
+    /// メールがこのメールボックスに含まれるか
+    fn contains(&self, email: &EmailData) -> bool {
+        email.is_sent_by(&self.username)
+    }
+}
+
+#[async_trait]
+impl MailStorage for MailboxStorage {
+    async fn save(&self, email: EmailData) -> Result<()> {
+        self.inner.save(email).await
+    }
+
+    async fn list(&self) -> Result<Vec<EmailData>> {
+        let emails = self.inner.list().await?;
+        Ok(emails
+            .into_iter()
+            .filter(|email| self.contains(email))
+            .collect())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<EmailData>> {
+        let email = self.inner.get(id).await?;
+        Ok(email.filter(|email| self.contains(email)))
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool> {
+        if self.get(id).await?.is_none() {
+            return Ok(false);
+        }
+        self.inner.delete(id).await
+    }
+
+    /// このメールボックスのメールだけを削除する
+    async fn clear(&self) -> Result<()> {
+        for email in self.list().await? {
+            self.inner.delete(email.get_id()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Local;
+
+    use super::*;
+    use crate::{envelope::Envelope, storage::memory::MemoryStorage};
+
+    fn email(auth_user: Option<&str>, to: &str) -> EmailData {
+        let mut envelope = Envelope::default();
+        envelope.set_auth_user(auth_user.map(str::to_string));
+        let content = format!("To: {}\r\nSubject: test\r\n\r\nbody\r\n", to);
+        EmailData::new(content.into_bytes(), envelope, Local::now())
+    }
+
+    #[tokio::test]
+    async fn only_exposes_the_users_mail() {
+        let sent = email(Some("alice@example.com"), "someone@example.com");
+        // 宛先がユーザー名のアドレスでも、他のユーザーが送信したメールは見せない
+        let addressed = email(Some("bob@example.com"), "Alice <alice@example.com>");
+        let other = email(Some("bob@example.com"), "someone@example.com");
+        let inner: Arc<dyn MailStorage> = Arc::new(MemoryStorage::with_emails(vec![
+            sent.clone(),
+            addressed.clone(),
+            other.clone(),
+        ]));
+        let mailbox = MailboxStorage::new(inner.clone(), "alice@example.com".to_string());
+
+        let ids: Vec<String> = mailbox
+            .list()
+            .await
+            .unwrap()
+            .iter()
+            .map(|email| email.get_id().clone())
+            .collect();
+        assert_eq!(ids, vec![sent.get_id().clone()]);
+        assert!(mailbox.get(other.get_id()).await.unwrap().is_none());
+        assert!(mailbox.get(addressed.get_id()).await.unwrap().is_none());
+
+        // 他のユーザーのメールは削除できない
+        assert!(!mailbox.delete(other.get_id()).await.unwrap());
+        mailbox.clear().await.unwrap();
+        let remaining: Vec<String> = inner
+            .list()
+            .await
+            .unwrap()
+            .iter()
+            .map(|email| email.get_id().clone())
+            .collect();
+        assert_eq!(
+            remaining,
+            vec![addressed.get_id().clone(), other.get_id().clone()]
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{email::EmailData, envelope::Envelope, mail_io};
+
+use super::{memory::MemoryStorage, MailStorage};
+
+const MAILDIR_SUB_DIRS: [&str; 4] = ["tmp", "new", "cur", "meta"];
+
+/// Maildir形式でディスクに保存するストレージ
+///
+/// `<root>/new/<id>` にメール本体、`<root>/meta/<id>.json` に受信時刻とエンベロープを保存する。
+/// 読み込みは起動時にロードしたメモリー上のキャッシュから行う
+pub struct MaildirStorage {
+    root: PathBuf,
+    cache: MemoryStorage,
+}
+
+/// メール本体以外に保存しておく情報
+#[derive(Serialize, Deserialize)]
+struct MailMeta {
+    id: String,
+    received_time: String,
+    envelope: Envelope,
+}
+
+impl MaildirStorage {
+    /// ## Summary
+    /// Maildirを開く(存在しなければ作成する)
+    ///
+    /// ## Parameters
+    /// - `root`: Maildirのルートディレクトリ
+    pub async fn open(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        for sub_dir in MAILDIR_SUB_DIRS {
+            fs::create_dir_all(root.join(sub_dir)).await?;
+        }
+
+        let emails = Self::load(&root).await?;
+        info!("Maildir {:?} から {} 件のメールを読み込みました", &root, emails.len());
+
+        Ok(Self {
+            root,
+            cache: MemoryStorage::with_emails(emails),
+        })
+    }
+
+    async fn load(root: &Path) -> Result<Vec<EmailData>> {
+        let mut emails = vec![];
+        for sub_dir in ["new", "cur"] {
+            let mut entries = fs::read_dir(root.join(sub_dir)).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                // Maildirのファイル名は "<id>:2,<flags>" の形式の場合がある
+                let id = file_name.split(':').next().unwrap_or_default().to_string();
+                if id.is_empty() || id.starts_with('.') {
+                    continue;
+                }
+
+                let raw = fs::read(entry.path()).await?;
+                let meta_path = Self::meta_path(root, &id);
+                let (received_time, envelope) = match fs::read(&meta_path).await {
+                    Ok(meta) => {
+                        let meta: MailMeta = serde_json::from_slice(&meta)?;
+                        let received_time = DateTime::parse_from_rfc3339(&meta.received_time)?
+                            .with_timezone(&Local);
+                        (received_time, meta.envelope)
+                    }
+                    Err(_) => {
+                        // 他のツールが置いたメールはファイルの更新日時を受信時刻とみなす
+                        warn!("{:?} のメタデータがありません", entry.path());
+                        let modified = entry.metadata().await?.modified()?;
+                        (DateTime::<Local>::from(modified), Envelope::default())
+                    }
+                };
+
+                emails.push(EmailData::restore(id, raw, envelope, received_time));
+            }
+        }
+
+        emails.sort_by_key(|email| *email.get_received_time());
+        Ok(emails)
+    }
+
+    fn meta_path(root: &Path, id: &str) -> PathBuf {
+        root.join("meta").join(format!("{}.json", id))
+    }
+
+    /// new/ と cur/ からメール本体のファイルを探す
+    async fn find_message_paths(&self, id: &str) -> Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        for sub_dir in ["new", "cur"] {
+            let mut entries = fs::read_dir(self.root.join(sub_dir)).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                if file_name.split(':').next() == Some(id) {
+                    paths.push(entry.path());
+                }
+            }
+        }
+        Ok(paths)
+    }
+}
+
+#[async_trait]
+impl MailStorage for MaildirStorage {
+    async fn save(&self, email: EmailData) -> Result<()> {
+        let meta = MailMeta {
+            id: email.get_id().clone(),
+            received_time: email.get_received_time().to_rfc3339(),
+            envelope: email.get_envelope().clone(),
+        };
+        fs::write(
+            Self::meta_path(&self.root, email.get_id()),
+            serde_json::to_vec(&meta)?,
+        )
+        .await?;
+        mail_io::save_data(&self.root, email.get_id(), email.get_raw()).await?;
+
+        self.cache.save(email).await
+    }
+
+    async fn list(&self) -> Result<Vec<EmailData>> {
+        self.cache.list().await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<EmailData>> {
+        self.cache.get(id).await
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool> {
+        if !self.cache.delete(id).await? {
+            return Ok(false);
+        }
+
+        for path in self.find_message_paths(id).await? {
+            fs::remove_file(path).await?;
+        }
+        let meta_path = Self::meta_path(&self.root, id);
+        if fs::try_exists(&meta_path).await? {
+            fs::remove_file(meta_path).await?;
+        }
+        Ok(true)
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.cache.clear().await?;
+        for sub_dir in ["new", "cur", "meta"] {
+            let mut entries = fs::read_dir(self.root.join(sub_dir)).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                fs::remove_file(entry.path()).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::SmtpPath;
+
+    #[tokio::test]
+    async fn persists_emails_across_restarts() {
+        let root =
+            std::env::temp_dir().join(format!("rust-mail-server-maildir-{}", uuid::Uuid::new_v4()));
+        let storage = MaildirStorage::open(&root).await.unwrap();
+        let mut envelope = Envelope::default();
+        envelope.begin(SmtpPath::parse("MAIL FROM:<a@example.com>", "MAIL FROM:").unwrap());
+        let email = EmailData::new(
+            b"Subject: saved\r\n\r\nbody\r\n".to_vec(),
+            envelope,
+            Local::now(),
+        );
+        storage.save(email.clone()).await.unwrap();
+        assert!(root.join("new").join(email.get_id()).exists());
+        // 他のツールが置いたメール(フラグ付きのファイル名・メタデータなし)
+        std::fs::write(
+            root.join("cur").join("foreign:2,S"),
+            b"Subject: foreign\r\n\r\n",
+        )
+        .unwrap();
+
+        let reopened = MaildirStorage::open(&root).await.unwrap();
+        let saved = reopened.get(email.get_id()).await.unwrap().unwrap();
+        assert_eq!(saved.get_raw(), email.get_raw());
+        assert_eq!(
+            saved.get_envelope().get_sender().as_deref(),
+            Some("a@example.com")
+        );
+        let foreign = reopened.get("foreign").await.unwrap().unwrap();
+        assert_eq!(foreign.get_envelope().get_sender(), None);
+
+        assert!(reopened.delete("foreign").await.unwrap());
+        assert!(!root.join("cur").join("foreign:2,S").exists());
+        reopened.clear().await.unwrap();
+        assert!(!root.join("new").join(email.get_id()).exists());
+        let reopened = MaildirStorage::open(&root).await.unwrap();
+        assert!(reopened.list().await.unwrap().is_empty());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+
+use crate::email::EmailData;
+
+use super::MailStorage;
+
+/// メモリー上にメールを保持するストレージ(再起動で消える)
+#[derive(Default)]
+pub struct MemoryStorage {
+    emails: Mutex<Vec<EmailData>>,
+}
+
+impl MemoryStorage {
+    pub fn with_emails(emails: Vec<EmailData>) -> Self {
+        Self {
+            emails: Mutex::new(emails),
+        }
+    }
+}
+
+#[async_trait]
+impl MailStorage for MemoryStorage {
+    async fn save(&self, email: EmailData) -> Result<()> {
+        self.emails.lock().await.push(email);
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<EmailData>> {
+        Ok(self.emails.lock().await.clone())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<EmailData>> {
+        let emails = self.emails.lock().await;
+        Ok(emails.iter().find(|email| email.get_id() == id).cloned())
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool> {
+        let mut emails = self.emails.lock().await;
+        if let Some(index) = emails.iter().position(|email| email.get_id() == id) {
+            emails.remove(index);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.emails.lock().await.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,29 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::email::EmailData;
+
+pub mod mailbox;
+pub mod maildir;
+pub mod memory;
+
+/// 受信メールの保存先を抽象化したtrait
+///
+/// SMTPサーバー・HTTPサーバーはこのtraitを経由してメールを読み書きする
+#[async_trait]
+pub trait MailStorage: Send + Sync {
+    /// メールを保存する
+    async fn save(&self, email: EmailData) -> Result<()>;
+
+    /// 保存済みのメールを受信順に全件取得する
+    async fn list(&self) -> Result<Vec<EmailData>>;
+
+    /// IDを指定してメールを取得する
+    async fn get(&self, id: &str) -> Result<Option<EmailData>>;
+
+    /// IDを指定してメールを削除する(削除できたかどうかを返す)
+    async fn delete(&self, id: &str) -> Result<bool>;
+
+    /// すべてのメールを削除する
+    async fn clear(&self) -> Result<()>;
+}
@@ -20,6 +20,10 @@ pub fn decode(input: &str) -> Result<String> {
     Ok(String::from_utf8(bytes)?)
 }
 
+pub fn encode(input: &[u8]) -> String {
+    general_purpose::STANDARD.encode(input)
+}
+
 pub fn deocde_bytes(input: &str) -> Result<Vec<u8>> {
     Ok(general_purpose::STANDARD.decode(input.trim())?)
 }